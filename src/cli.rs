@@ -1,13 +1,15 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, process::ExitCode};
 
-use clap::{Parser, ValueEnum};
+use clap::{builder::{PossibleValuesParser, TypedValueParser}, Parser, ValueEnum};
 use colored::Colorize;
 use log::LevelFilter;
 
-use crate::{cli::threads_config::ThreadsConfig, task::{Task, TaskInvocation, TaskRef, Taskfile, Workspace}};
+use crate::{cli::{resource_limit::ResourceLimit, threads_config::ThreadsConfig}, run::{dependency_resolution::{build_annotated_dependency_graph, export}, display_args, execution::TaskExecutionError, interrupt::Interrupt, logs, report::{self, RunReport, TaskStatus}, RunError}, task::{OutputMode, ResolvedTaskInvocation, Task, TaskInvocation, TaskRef, Taskfile, Workspace}};
 
+pub mod resource_limit;
 pub mod threads_config;
 pub mod value_parser;
+mod watch;
 
 /// Command-line interface for the birb task runner.
 ///
@@ -48,6 +50,9 @@ pub enum Command {
     Run(Run),
     Clean(Clean),
     CleanOnly(CleanOnly),
+    Graph(Graph),
+    Watch(Watch),
+    Logs(Logs),
 }
 
 /// List all tasks
@@ -82,11 +87,45 @@ pub struct Run {
     #[clap(default_value = "default")]
     task: String,
 
+    #[clap(flatten)]
+    args: InvocationArgs,
+
+    /// Don't run anything, only print which tasks would run and why
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+
+    /// Print a report of what happened to every task at the end of the run
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    report: Option<ReportFormat>,
+
     #[clap(flatten)]
     options: CliRunOptions,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(ValueEnum)]
+pub enum ReportFormat {
+    /// Summary table
+    Table,
+    Json,
+}
+
+/// Arguments passed to the invoked task
+#[derive(Parser, Debug)]
+pub struct InvocationArgs {
+    /// Task arguments, in the form `key=value`
+    ///
+    /// The task name can't be omitted when arguments are given this way,
+    /// e.g. `birb run default key=value`, otherwise use `--arg`.
+    #[clap(value_name = "KEY=VALUE")]
+    args: Vec<String>,
+
+    /// Task argument in the form `key=value`, can be repeated
+    #[clap(short = 'a', long = "arg", value_name = "KEY=VALUE")]
+    arg: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[derive(Parser)]
 pub struct CliRunOptions {
     /// Less verbose, only show progress and not the tasks name and status
@@ -96,8 +135,73 @@ pub struct CliRunOptions {
     /// Number of threads to use for parallel execution.
     ///
     /// Using this option enable parallel execution mode using the specified number of threads.
+    /// The steps share these threads with the nested `make`, `cargo` or `birb` builds through
+    /// a jobserver. Under the jobserver of a parent process, the parent's tokens are used instead,
+    /// and at most this many tasks (by default the number of CPUs) run at the same time.
     #[clap(short = 'j', long)]
     pub threads: Option<ThreadsConfig>,
+
+    /// Keep running the tasks that don't depend on a failed task,
+    /// and report all the failures at the end
+    #[clap(short = 'k', long)]
+    pub keep_going: bool,
+
+    /// Available amount of a resource or lock in parallel runs, overriding the
+    /// `limits` of the taskfile. The `cpu` limit defaults to the number of threads.
+    #[clap(long = "limit", value_name = "NAME=AMOUNT")]
+    pub limits: Vec<ResourceLimit>,
+
+    /// How the output of the tasks is shown in parallel runs, overriding the
+    /// `output` of the taskfiles and tasks. Defaults to `prefixed`.
+    #[clap(long, value_name = "MODE", value_parser = PossibleValuesParser::new(OutputMode::NAMES).map(|name| OutputMode::from_name(&name).unwrap()))]
+    pub output: Option<OutputMode>,
+
+    /// Run the steps of every task under pseudo-terminals, as with `tty: true`,
+    /// so that tools keep their colours and progress output
+    #[clap(long)]
+    pub tty: bool,
+
+    /// Seconds given to running steps to exit after Ctrl-C, before they are killed
+    #[clap(long, value_name = "SECONDS", default_value_t = 5)]
+    pub grace_period: u64,
+
+    /// How the progress of the run is reported. Defaults to `ci` when the `CI`
+    /// or `GITHUB_ACTIONS` environment variable is set, `human` otherwise.
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    pub message_format: Option<MessageFormat>,
+
+    /// Write the JSON messages to this file instead of stdout
+    #[clap(long, value_name = "PATH")]
+    pub message_file: Option<PathBuf>,
+
+    /// Write a Chrome trace of the run to this file, it can be opened
+    /// with Perfetto or `chrome://tracing`
+    #[clap(long, value_name = "PATH")]
+    pub trace: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(ValueEnum)]
+pub enum MessageFormat {
+    /// Progress bars and task output for humans
+    Human,
+    /// Newline-delimited JSON events
+    Json,
+    /// Plain logs for CI, with the output of the tasks in GitHub Actions groups
+    /// and the failures as error annotations
+    Ci,
+}
+
+impl MessageFormat {
+    /// `ci` under a CI service, as told by the `CI` or `GITHUB_ACTIONS` environment variables, `human` otherwise
+    pub fn detect() -> Self {
+        let is_set = |name| std::env::var(name).is_ok_and(|value| !matches!(value.as_str(), "" | "0" | "false"));
+        if is_set("GITHUB_ACTIONS") || is_set("CI") {
+            Self::Ci
+        } else {
+            Self::Human
+        }
+    }
 }
 
 /// Recursively clean a task
@@ -105,12 +209,87 @@ pub struct CliRunOptions {
 pub struct Clean {
     #[clap(default_value = "default")]
     task: String,
+
+    #[clap(flatten)]
+    args: InvocationArgs,
 }
 
 /// Clean a single task (non recursive)
 #[derive(Parser, Debug)]
 pub struct CleanOnly {
     task: String,
+
+    #[clap(flatten)]
+    args: InvocationArgs,
+}
+
+/// Print the dependency graph of a task
+///
+/// Edges go from a task to the tasks it has to wait for, edges
+/// coming from `after` constraints are drawn dashed.
+#[derive(Parser, Debug)]
+#[clap(verbatim_doc_comment)]
+pub struct Graph {
+    #[clap(default_value = "default")]
+    task: String,
+
+    #[clap(flatten)]
+    args: InvocationArgs,
+
+    /// Graph format
+    #[clap(short, long, value_enum, default_value = "dot")]
+    format: GraphFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+    /// Mermaid flowchart
+    Mermaid,
+    /// Nodes and edges as JSON, for other tools
+    Json,
+}
+
+/// Run a task, then run it again every time one of its sources changes
+///
+/// All the sources of the tasks in the dependency graph and the
+/// taskfiles themselves are watched. When a taskfile changes, the
+/// taskfiles are reloaded. A running build is cancelled when a new
+/// change arrives.
+#[derive(Parser, Debug)]
+#[clap(verbatim_doc_comment)]
+pub struct Watch {
+    #[clap(default_value = "default")]
+    task: String,
+
+    #[clap(flatten)]
+    args: InvocationArgs,
+
+    /// Time to wait for changes to settle before re-running, in milliseconds
+    #[clap(long, default_value = "200", value_name = "MS")]
+    debounce: u64,
+
+    #[clap(flatten)]
+    options: CliRunOptions,
+}
+
+/// Print the log of a task from the last run where it ran
+///
+/// Every run logs the output of its tasks in `.birb/logs`,
+/// the logs of the last 20 runs that ran a task are kept.
+#[derive(Parser, Debug)]
+#[clap(verbatim_doc_comment)]
+pub struct Logs {
+    task: String,
+
+    #[clap(flatten)]
+    args: InvocationArgs,
+
+    /// Only print the path of the log file
+    #[clap(long)]
+    path: bool,
 }
 
 pub fn main(args: &Cli, init_env_logger: bool) -> anyhow::Result<()> {
@@ -138,19 +317,114 @@ pub fn main(args: &Cli, init_env_logger: bool) -> anyhow::Result<()> {
         &cwd
     };
 
+    if let Command::Watch(args) = &args.command {
+        return watch::watch(path, args);
+    }
+
     let (workspace, tasks_id) = Workspace::from_main(path)?;
     let tasks = workspace.get(&tasks_id).expect("Failed to get taskfile from workspace");
 
     match &args.command {
         Command::List(args) => list(&tasks, args)?,
-        Command::Run(args) => tasks.invoke(&workspace, &TaskInvocation::no_args(TaskRef::parse(&args.task)), &args.options)?,
-        Command::Clean(args) => tasks.clean(&workspace, &TaskInvocation::no_args(TaskRef::parse(&args.task)), true)?,
-        Command::CleanOnly(args) => tasks.clean(&workspace, &TaskInvocation::no_args(TaskRef::parse(&args.task)), false)?,
+        Command::Run(args) if args.dry_run => dry_run(&workspace, tasks, &invocation(&workspace, tasks, &args.task, &args.args)?)?,
+        Command::Run(args) => {
+            let report = tasks.invoke(&workspace, &invocation(&workspace, tasks, &args.task, &args.args)?, &args.options, &Interrupt::ctrl_c())?;
+            if let Some(format) = args.report {
+                print_report(&report, format)?;
+            }
+            report.into_result()?
+        },
+        Command::Clean(args) => tasks.clean(&workspace, &invocation(&workspace, tasks, &args.task, &args.args)?, true)?,
+        Command::CleanOnly(args) => tasks.clean(&workspace, &invocation(&workspace, tasks, &args.task, &args.args)?, false)?,
+        Command::Graph(args) => graph(&workspace, tasks, args)?,
+        Command::Logs(args) => logs(&workspace, tasks, args)?,
+        Command::Watch(_) => unreachable!("watch mode manages its own workspace"),
     };
 
     Ok(())
 }
 
+/// Prints the error returned by [`main`] and returns the code the process should exit with
+///
+/// A failed step is reported with its command and last output lines, and its
+/// exit code is propagated. An interrupted run lists the tasks that were stopped.
+/// Every failure is reported, followed by the tasks that were not run.
+pub fn report_error(error: &anyhow::Error) -> ExitCode {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let display = |invocation: &ResolvedTaskInvocation| {
+        let args = display_args(invocation);
+        format!("{}{}{args}", invocation.r#ref.display_relative(&cwd).to_string().bold(), if args.is_empty() { "" } else { " " })
+    };
+
+    match error.downcast_ref::<RunError>() {
+        Some(RunError::Interrupted { aborted }) => {
+            eprintln!("{} interrupted", "error:".red().bold());
+            if !aborted.is_empty() {
+                eprintln!("  {}", "aborted tasks:".bold());
+                for invocation in aborted {
+                    eprintln!("    {}", display(invocation));
+                }
+            }
+            // conventional exit code for SIGINT
+            ExitCode::from(130)
+        },
+        Some(RunError::Failed { failures, skipped }) => {
+            let codes = failures.iter().map(|e| report_task_error(e, display)).collect::<Vec<_>>();
+            if !skipped.is_empty() {
+                eprintln!("{} {} tasks were not run:", "error:".red().bold(), skipped.len());
+                for invocation in skipped {
+                    eprintln!("    {}", display(invocation));
+                }
+            }
+            // the first failure decides the exit code
+            codes.into_iter().next().unwrap_or(ExitCode::FAILURE)
+        },
+        _ => report_task_error(error.as_ref(), display),
+    }
+}
+
+/// Prints a single error, with the details of the step if it comes from a failed step
+fn report_task_error(error: &(dyn std::error::Error + 'static), display: impl Fn(&ResolvedTaskInvocation) -> String) -> ExitCode {
+    let step_failure = std::iter::successors(Some(error), |e| e.source()).find_map(|e| match e.downcast_ref::<TaskExecutionError>() {
+        Some(TaskExecutionError::StepFailed { invocation, failure }) => Some((invocation, failure)),
+        _ => None,
+    });
+
+    let Some((invocation, failure)) = step_failure else {
+        eprintln!("{} {error}", "error:".red().bold());
+        return ExitCode::FAILURE;
+    };
+
+    eprintln!("{} task {} failed", "error:".red().bold(), display(invocation));
+    eprintln!("  {} {}", "step:".bold(), failure.step + 1);
+    eprintln!("  {} {}", "status:".bold(), failure.status_description());
+    eprintln!("  {}", "command:".bold());
+    for line in failure.command.lines() {
+        eprintln!("    {} {line}", "|".dimmed());
+    }
+    if !failure.output_tail.is_empty() {
+        eprintln!("  {}", "last output:".bold());
+        for line in &failure.output_tail {
+            eprintln!("    {} {line}", "|".dimmed());
+        }
+    }
+
+    ExitCode::from(failure.exit_code())
+}
+
+/// Builds the requested invocation, parsing the arguments against the task parameters
+fn invocation(workspace: &Workspace, tasks: &Taskfile, task: &str, args: &InvocationArgs) -> anyhow::Result<TaskInvocation<TaskRef>> {
+    if task.contains('=') {
+        anyhow::bail!("`{task}` is not a task name, give the task before its arguments (e.g. `default {task}`) or use `--arg {task}`");
+    }
+    let r#ref = TaskRef::parse(task);
+    let (_, task) = workspace
+        .resolve_task(tasks, &r#ref)
+        .ok_or_else(|| RunError::TaskNotFound(r#ref.clone()))?;
+    let args = task.parse_args(args.args.iter().chain(&args.arg))?;
+    Ok(TaskInvocation { r#ref, args })
+}
+
 fn list(tasks: &Taskfile, args: &List) -> anyhow::Result<()> {
     if let Some(format) = args.format {
         if format != OutputFormat::Json {
@@ -217,6 +491,90 @@ fn list(tasks: &Taskfile, args: &List) -> anyhow::Result<()> {
     Ok(())
 }
 
+fn dry_run(workspace: &Workspace, tasks: &Taskfile, invocation: &TaskInvocation<TaskRef>) -> anyhow::Result<()> {
+    let cwd = std::env::current_dir()?;
+
+    for (invocation, decision) in crate::run::explain(workspace, tasks, invocation)? {
+        let name = invocation.r#ref.display_relative(&cwd).to_string();
+        let args = display_args(&invocation);
+        if decision.should_run() {
+            println!("    {} {args}\t{} {}", name.bold().green(), "would run:".bold(), decision.display_relative(&cwd));
+        } else {
+            println!("    {} {args}\t{}", name.bold().cyan(), decision.display_relative(&cwd));
+        }
+    }
+
+    Ok(())
+}
+
+fn print_report(report: &RunReport, format: ReportFormat) -> anyhow::Result<()> {
+    let cwd = std::env::current_dir()?;
+
+    if format == ReportFormat::Json {
+        println!("{}", serde_json::to_string(&report::to_json(report, &cwd))?);
+        return Ok(());
+    }
+
+    let rows = report.tasks.iter().map(|task| {
+        let args = display_args(&task.invocation);
+        let name = format!("{}{}{args}", task.invocation.r#ref.display_relative(&cwd), if args.is_empty() { "" } else { " " });
+        let duration = task.duration().map_or(String::new(), |d| format!("{:.2}s", d.as_secs_f64()));
+        let details = match (&task.exit_code, &task.decision) {
+            (Some(code), _) => format!("exit code {code}"),
+            (None, Some(decision)) if task.status != TaskStatus::UpToDate => decision.display_relative(&cwd).to_string(),
+            _ => String::new(),
+        };
+        (name, task.status, duration, details)
+    }).collect::<Vec<_>>();
+
+    let width = rows.iter().map(|(name, ..)| name.len()).max().unwrap_or(0).max("task".len());
+    println!("{:<width$}  {:<11}  {:>9}  {}", "task".bold(), "status".bold(), "duration".bold(), "details".bold());
+    for (name, status, duration, details) in rows {
+        let status_text = format!("{:<11}", status.to_string());
+        let status_text = match status {
+            TaskStatus::Ran => status_text.green(),
+            TaskStatus::UpToDate => status_text.cyan(),
+            TaskStatus::Failed => status_text.red().bold(),
+            TaskStatus::Skipped => status_text.bright_black(),
+            TaskStatus::Interrupted => status_text.yellow(),
+        };
+        println!("{name:<width$}  {status_text}  {duration:>9}  {details}");
+    }
+
+    Ok(())
+}
+
+fn graph(workspace: &Workspace, tasks: &Taskfile, args: &Graph) -> anyhow::Result<()> {
+    let invocation = invocation(workspace, tasks, &args.task, &args.args)?;
+    let graph = build_annotated_dependency_graph(workspace, tasks, &invocation)?;
+    let cwd = std::env::current_dir()?;
+
+    match args.format {
+        GraphFormat::Dot => print!("{}", export::to_dot(&graph, &cwd)),
+        GraphFormat::Mermaid => print!("{}", export::to_mermaid(&graph, &cwd)),
+        GraphFormat::Json => println!("{}", serde_json::to_string(&export::to_json(&graph))?),
+    }
+
+    Ok(())
+}
+
+fn logs(workspace: &Workspace, tasks: &Taskfile, args: &Logs) -> anyhow::Result<()> {
+    let invocation = invocation(workspace, tasks, &args.task, &args.args)?;
+    let (resolved, _) = workspace
+        .resolve_invocation(tasks, &invocation)
+        .ok_or_else(|| RunError::TaskNotFound(invocation.r#ref.clone()))?;
+    let path = logs::find_last(tasks.state_dir(), &resolved)?
+        .ok_or_else(|| anyhow::anyhow!("No log of `{}`, it did not run in the last runs", format!("{} {}", args.task, display_args(&resolved)).trim_end()))?;
+
+    if args.path {
+        println!("{}", path.display());
+    } else {
+        std::io::copy(&mut std::fs::File::open(&path)?, &mut std::io::stdout().lock())?;
+    }
+
+    Ok(())
+}
+
 fn task_short(task: &Task) -> Option<String> {
     let desc: &str = task.description.as_ref()?;
 
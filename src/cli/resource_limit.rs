@@ -0,0 +1,29 @@
+use std::str::FromStr;
+
+use crate::task::resources::parse_amount;
+
+/// Available amount of a resource or lock, given as `NAME=AMOUNT` on the command line
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ResourceLimit {
+    pub name: String,
+    pub amount: u64,
+}
+
+impl FromStr for ResourceLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, amount) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=AMOUNT but got `{s}`"))?;
+        if name.is_empty() {
+            return Err(format!("missing resource name in `{s}`"));
+        }
+        let amount = parse_amount(amount)
+            .ok_or_else(|| format!("invalid amount `{amount}`, expected an integer with an optional K, M, G or T suffix"))?;
+        Ok(Self {
+            name: name.to_string(),
+            amount,
+        })
+    }
+}
@@ -0,0 +1,346 @@
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}, sync::mpsc::{self, Receiver, RecvTimeoutError}, thread, time::{Duration, Instant}};
+
+use colored::Colorize;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::{
+    cli::{invocation, Watch},
+    run::{dependency_resolution::build_dependency_graph, interrupt::Interrupt, report::RunReport},
+    task::{PathPatterns, TaskInvocation, TaskRef, Taskfile, TaskfileId, Workspace},
+};
+
+/// How often we check for Ctrl-C while waiting for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub(crate) fn watch(path: &Path, args: &Watch) -> anyhow::Result<()> {
+    let ctrl_c = Interrupt::ctrl_c();
+    let debounce = Duration::from_millis(args.debounce);
+
+    // One watcher for the whole session, so that the changes made while
+    // reloading or between two builds are not lost.
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    let mut subscriptions = Subscriptions::default();
+
+    // if the taskfiles cannot be loaded, we keep watching the previous files
+    let mut watched = WatchedFiles::default();
+    if let Some(source) = Taskfile::find_taskfile(path) {
+        watched.files.insert(canonical(source.path()));
+    }
+    subscriptions.update(&mut watcher, watched.watches());
+
+    while !ctrl_c.is_triggered() {
+        let loaded = load(path, args);
+        match &loaded {
+            Ok(build) => watched = build.watched.clone(),
+            Err(e) => eprintln!("{} {e}", "error:".red().bold()),
+        }
+        subscriptions.update(&mut watcher, watched.watches());
+
+        let build_interrupt = ctrl_c.child();
+        thread::scope(|s| {
+            if let Ok(build) = &loaded {
+                let build_interrupt = &build_interrupt;
+                s.spawn(move || {
+                    let tasks = build.workspace.get(&build.id).expect("Failed to get taskfile from workspace");
+                    match tasks.invoke(&build.workspace, &build.invocation, &args.options, build_interrupt).and_then(RunReport::into_result) {
+                        Ok(()) => println!("{} waiting for changes...", "watch:".cyan().bold()),
+                        Err(_) if build_interrupt.is_triggered() => println!("{} build cancelled", "watch:".cyan().bold()),
+                        Err(e) => eprintln!("{} {e}\n{} waiting for changes...", "error:".red().bold(), "watch:".cyan().bold()),
+                    }
+                });
+            }
+
+            wait_for_change(&rx, &watched, debounce, &ctrl_c);
+
+            // stop the running build (if any) before starting a new one
+            build_interrupt.trigger();
+        });
+    }
+
+    Ok(())
+}
+
+struct Build {
+    workspace: Workspace,
+    id: TaskfileId,
+    invocation: TaskInvocation<TaskRef>,
+    watched: WatchedFiles,
+}
+
+fn load(path: &Path, args: &Watch) -> anyhow::Result<Build> {
+    let (workspace, id) = Workspace::from_main(path)?;
+    let tasks = workspace.get(&id).expect("Failed to get taskfile from workspace");
+    let invocation = invocation(&workspace, tasks, &args.task, &args.args)?;
+    let watched = WatchedFiles::collect(&workspace, tasks, &invocation)?;
+    Ok(Build {
+        workspace,
+        id,
+        invocation,
+        watched,
+    })
+}
+
+/// The paths are canonical, like the paths of the events
+#[derive(Debug, Clone, Default)]
+struct WatchedFiles {
+    /// Files that trigger a re-run when they change
+    files: HashSet<PathBuf>,
+    /// Source patterns, changes to the files they match trigger a re-run
+    patterns: Vec<PathPatterns>,
+    /// Files and directories written by the build itself, changes to these are ignored
+    outputs: HashSet<PathBuf>,
+}
+
+impl WatchedFiles {
+    fn collect(workspace: &Workspace, current: &Taskfile, invocation: &TaskInvocation<TaskRef>) -> anyhow::Result<Self> {
+        let mut this = Self::default();
+
+        for taskfile in workspace.taskfiles() {
+            match &taskfile.id {
+                TaskfileId::Path(path) => this.files.insert(canonical(path)),
+            };
+        }
+
+        let (_graph, instantiations) = build_dependency_graph(workspace, current, invocation)?;
+        for task in instantiations.values() {
+            this.files.extend(task.resolve_sources().iter().map(|source| canonical(source)));
+            this.patterns.push(
+                PathPatterns::new(canonical(&task.body.workdir), task.body.sources.iter().map(String::as_str))
+                    .expect("patterns are validated when the task is instantiated"),
+            );
+            this.outputs.extend(task.resolve_outputs().map(|output| canonical(output.as_ref())));
+        }
+
+        Ok(this)
+    }
+
+    /// The directories to watch and how
+    fn watches(&self) -> HashMap<PathBuf, RecursiveMode> {
+        // Files are watched through their parent directory, editors often
+        // replace files instead of writing them in place.
+        let mut watches = self
+            .files
+            .iter()
+            .filter_map(|file| file.parent())
+            .map(|dir| (dir.to_path_buf(), RecursiveMode::NonRecursive))
+            .collect::<HashMap<_, _>>();
+
+        let roots = self
+            .patterns
+            .iter()
+            .flat_map(|patterns| patterns.roots())
+            .filter(|root| root.is_dir());
+        for root in roots {
+            watches.insert(root.to_path_buf(), RecursiveMode::Recursive);
+        }
+
+        watches
+    }
+
+    fn is_relevant(&self, event: &Event) -> bool {
+        if matches!(event.kind, EventKind::Access(_)) {
+            return false;
+        }
+
+        event.paths.iter().map(|path| canonical(path)).any(|path| {
+            !self.is_output(&path)
+                && (self.files.contains(&path) || self.patterns.iter().any(|patterns| patterns.matches(&path)))
+        })
+    }
+
+    /// Returns `true` for outputs and the files inside output directories
+    fn is_output(&self, path: &Path) -> bool {
+        path.ancestors().any(|ancestor| self.outputs.contains(ancestor))
+    }
+}
+
+/// The directories the watcher is subscribed to
+#[derive(Debug, Default)]
+struct Subscriptions(HashMap<PathBuf, RecursiveMode>);
+
+impl Subscriptions {
+    /// Subscribes to `watches` only, keeping the subscriptions that didn't change
+    fn update(&mut self, watcher: &mut RecommendedWatcher, watches: HashMap<PathBuf, RecursiveMode>) {
+        let stale = self
+            .0
+            .iter()
+            .filter(|(path, mode)| watches.get(*path) != Some(mode))
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        for path in stale {
+            self.0.remove(&path);
+            // fails if the directory was removed, the watch is gone anyway
+            if let Err(e) = watcher.unwatch(&path) {
+                log::debug!("Failed to unwatch {}: {e}", path.display());
+            }
+        }
+
+        for (path, mode) in watches {
+            if self.0.contains_key(&path) {
+                continue;
+            }
+            match watcher.watch(&path, mode) {
+                Ok(()) => {
+                    self.0.insert(path, mode);
+                },
+                Err(e) => log::warn!("Failed to watch {}: {e}", path.display()),
+            }
+        }
+    }
+}
+
+/// Blocks until a relevant change happened and no other relevant change happened for `debounce`
+///
+/// Irrelevant events, e.g. the outputs written by the running build, don't delay the rebuild.
+fn wait_for_change(
+    rx: &Receiver<notify::Result<Event>>,
+    watched: &WatchedFiles,
+    debounce: Duration,
+    ctrl_c: &Interrupt,
+) {
+    loop {
+        if ctrl_c.is_triggered() {
+            return;
+        }
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) if watched.is_relevant(&event) => {
+                log::debug!("Change detected: {:?}", event.paths);
+                break;
+            }
+            Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => {}
+            Ok(Err(e)) => log::warn!("Watch error: {e}"),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+
+    let mut deadline = Instant::now() + debounce;
+    loop {
+        if ctrl_c.is_triggered() {
+            return;
+        }
+        let Some(remaining) = deadline.checked_duration_since(Instant::now()).filter(|d| !d.is_zero()) else {
+            return;
+        };
+        match rx.recv_timeout(remaining.min(POLL_INTERVAL)) {
+            Ok(Ok(event)) if watched.is_relevant(&event) => {
+                log::debug!("Change detected while debouncing: {:?}", event.paths);
+                deadline = Instant::now() + debounce;
+            }
+            Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => {}
+            Ok(Err(e)) => log::warn!("Watch error: {e}"),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// The canonical path, paths that don't exist (yet) are resolved from their closest existing ancestor
+fn canonical(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => canonical(parent).join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use notify::event::{CreateKind, ModifyKind};
+
+    use super::*;
+
+    /// A project with a source, its outputs and a source written by the build,
+    /// the patterns are relative to `base`, a path to the project
+    fn project(dir: &Path, base: &Path) -> WatchedFiles {
+        fs::create_dir_all(dir.join("src/gen")).unwrap();
+        fs::create_dir_all(dir.join("out/deep")).unwrap();
+        fs::write(dir.join("src/main.c"), "").unwrap();
+        WatchedFiles {
+            files: [canonical(&dir.join("tasks.yaml"))].into_iter().collect(),
+            patterns: vec![PathPatterns::new(canonical(base), ["src/**/*.c"]).unwrap()],
+            outputs: [canonical(&dir.join("out")), canonical(&dir.join("src/gen/version.c"))].into_iter().collect(),
+        }
+    }
+
+    fn modified(watched: &WatchedFiles, path: PathBuf) -> bool {
+        watched.is_relevant(&Event::new(EventKind::Modify(ModifyKind::Any)).add_path(path))
+    }
+
+    #[test]
+    fn relevant_events() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("project");
+        fs::create_dir_all(&dir).unwrap();
+        let watched = project(&dir, &dir);
+        let modified = |path| modified(&watched, path);
+
+        assert!(modified(dir.join("src/main.c")));
+        assert!(modified(dir.join("tasks.yaml")));
+        // not created yet
+        assert!(modified(dir.join("src/new/file.c")));
+
+        // written by the build
+        assert!(!modified(dir.join("out/deep/main.o")));
+        assert!(!modified(dir.join("out")));
+        assert!(!modified(dir.join("src/gen/version.c")));
+        // not a source
+        assert!(!modified(dir.join("src/notes.txt")));
+        assert!(!modified(tmp.path().join("elsewhere.c")));
+
+        let created = Event::new(EventKind::Create(CreateKind::File)).add_path(dir.join("out/new.o")).add_path(dir.join("src/new.c"));
+        assert!(watched.is_relevant(&created));
+    }
+
+    /// The events and the patterns may use other paths to the same files
+    #[cfg(unix)]
+    #[test]
+    fn relevant_events_through_symlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("project");
+        fs::create_dir_all(&dir).unwrap();
+        let link = tmp.path().join("link");
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+        let watched = project(&dir, &link);
+        let modified = |path| modified(&watched, path);
+
+        assert!(modified(dir.join("src/main.c")));
+        assert!(modified(link.join("src/main.c")));
+        assert!(modified(link.join("tasks.yaml")));
+        assert!(modified(link.join("src/new/file.c")));
+
+        assert!(!modified(link.join("out")));
+        assert!(!modified(link.join("out/deep/main.o")));
+        assert!(!modified(link.join("src/gen/version.c")));
+        assert!(!modified(link.join("src/notes.txt")));
+    }
+
+    /// Changes made between two builds, e.g. while reloading, are still reported
+    #[test]
+    fn changes_while_resubscribing_are_kept() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = canonical(tmp.path());
+        let watched = project(&dir, &dir);
+
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx).unwrap();
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.update(&mut watcher, watched.watches());
+
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+        subscriptions.update(&mut watcher, watched.watches());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let remaining = deadline.checked_duration_since(Instant::now()).expect("change not reported");
+            match rx.recv_timeout(remaining) {
+                Ok(Ok(event)) if watched.is_relevant(&event) => break,
+                Ok(_) => {},
+                Err(e) => panic!("change not reported: {e}"),
+            }
+        }
+    }
+}
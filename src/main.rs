@@ -1,10 +1,17 @@
-use birb_task::cli::Cli;
+use std::process::ExitCode;
+
+use birb_task::{cli::Cli, run::jobserver::Jobserver};
 use clap::Parser;
 
-fn main() -> anyhow::Result<()> {
+fn main() -> ExitCode {
+    // SAFETY: no file has been opened yet
+    unsafe { Jobserver::inherit() };
+
     let args = Cli::parse();
 
     log::info!("Starting birb task runner");
-    birb_task::cli::main(&args, true)
+    match birb_task::cli::main(&args, true) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => birb_task::cli::report_error(&e),
+    }
 }
-
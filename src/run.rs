@@ -1,12 +1,17 @@
-use std::sync::{atomic::AtomicBool, Arc, Mutex};
+use std::{collections::{BTreeMap, HashMap, HashSet}, sync::{Arc, Mutex}, time::Duration};
 
+pub mod interrupt;
+pub mod jobserver;
+pub mod logs;
+pub mod report;
 pub mod run_manager;
+pub mod trace;
 
 use crate::{
     run::{
         dependency_resolution::{build_dependency_graph, topological_sort::topological_sort, DependencyGraphConstructionError, TopologicalSortError},
-        execution::{clean_instantiated_task, clean_single_task, maybe_run_single_task, scheduler::execute_tasks_concurrently, triggers::NaiveTriggerChecker, TaskExecutionError}, run_manager::{RunExecution, RunManager},
-    }, task::{ResolvedTaskInvocation, TaskInvocation, TaskRef, Taskfile, Workspace}
+        execution::{clean_instantiated_task, clean_single_task, maybe_run_single_task, scheduler::{execute_tasks_concurrently, SchedulerConfig}, triggers::{persistent::PersistentTriggerChecker, RunReason, TaskTriggerChecker, TriggerDecision}, TaskExecutionError}, interrupt::Interrupt, jobserver::Jobserver, logs::RunLogs, report::{RunReport, TaskReport, TaskStatus}, run_manager::{RunExecution, RunManager, TaskExecutionContext}, trace::Trace,
+    }, task::{resources::CPU, InstantiatedTask, ResolvedTaskInvocation, TaskInvocation, TaskRef, Taskfile, Workspace}
 };
 
 pub mod dependency_resolution;
@@ -18,7 +23,7 @@ pub mod execution;
 pub enum RunError {
     #[error("Failed to build dependency graph: {0}")]
     DependencyGraphConstructionError(#[from] DependencyGraphConstructionError),
-    #[error("Failed to build dependency graph: {0}")]
+    #[error("{0}")]
     ExecutionError(#[from] TaskExecutionError),
     #[error("Topological sort error: {0}")]
     TopologicalSortError(#[from] TopologicalSortError),
@@ -30,99 +35,320 @@ pub enum RunError {
     BeginTaskError(anyhow::Error),
     #[error("Manager run execution failed enter task: {0}")]
     EnterTaskError(anyhow::Error),
+    #[error("{} tasks failed", failures.len())]
+    Failed {
+        /// Errors of the failed tasks, in completion order
+        failures: Vec<RunError>,
+        /// Tasks that were not run because they depend on a failed task
+        skipped: Vec<ResolvedTaskInvocation>,
+    },
+    #[error("Execution interrupted")]
+    Interrupted {
+        /// Tasks that were running and had to be stopped
+        aborted: Vec<ResolvedTaskInvocation>,
+    },
+}
+
+/// Options of a run that don't depend on how it is displayed
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Keep running the tasks that don't depend on a failed task
+    pub keep_going: bool,
+    /// Records a profile of the run
+    pub trace: Option<Arc<Trace>>,
+    /// Limits of resources and locks given on the command line, they take
+    /// precedence over the limits of the taskfile
+    pub limits: BTreeMap<String, u64>,
+    /// Tokens shared with the nested builds, in parallel runs every running task holds one
+    pub jobserver: Option<Jobserver>,
+    /// Where the output of the tasks is logged
+    pub logs: Option<Arc<RunLogs>>,
 }
 
-pub fn run(
+/// Runs the requested task and its dependencies one at a time
+///
+/// Failed and interrupted tasks are part of the returned report, see
+/// [`RunReport::into_result`]. An error is only returned if the run could not
+/// be started.
+pub async fn run(
     workspace: &Workspace,
     current: &Taskfile,
     req: &TaskInvocation<TaskRef>,
     run_manager: impl RunManager,
-) -> Result<(), RunError> {
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-
-    ctrlc::set_handler(move || {
-        r.store(false, std::sync::atomic::Ordering::SeqCst);
-    }).unwrap();
-
+    options: &RunOptions,
+    interrupt: &Interrupt,
+) -> Result<RunReport, RunError> {
     let (deps_graph, instantiations) = build_dependency_graph(workspace, current, req)?;
 
     let sorted = topological_sort(&deps_graph)?;
 
-    let mut trigger_checker = NaiveTriggerChecker::default();
+    let mut trigger_checker = PersistentTriggerChecker::load(current.state_dir());
     let execution = run_manager.begin(sorted.iter().rev()).map_err(RunError::BeginTaskError)?;
-    for invocation in sorted.iter().rev() {
-        if !running.load(std::sync::atomic::Ordering::SeqCst) {
-            return Err(RunError::ExecutionError(TaskExecutionError::Other(anyhow::anyhow!("Execution interrupted"))));
+
+    let mut report = RunReport {
+        tasks: sorted.iter().rev().map(TaskReport::new).collect(),
+        interrupted: false,
+    };
+
+    // only used in keep-going mode
+    let mut not_fulfilled = HashSet::new();
+
+    for task_report in &mut report.tasks {
+        if interrupt.is_triggered() {
+            report.interrupted = true;
+            break;
+        }
+        let invocation = &task_report.invocation.clone(); // TODO avoid clone
+        if deps_graph.get(invocation).is_some_and(|deps| deps.iter().any(|dep| not_fulfilled.contains(dep))) {
+            not_fulfilled.insert(invocation.clone());
+            continue;
+        }
+        run_task(current, &instantiations, &execution, &mut trigger_checker, interrupt, options, task_report).await;
+        if !task_report.status.is_success() {
+            if !options.keep_going || interrupt.is_triggered() {
+                report.interrupted |= interrupt.is_triggered();
+                break;
+            }
+            not_fulfilled.insert(invocation.clone());
         }
-        maybe_run_single_task(
-            current,
-            &instantiations,
-            invocation,
-            &mut trigger_checker,
-            execution.enter_task(invocation).map_err(RunError::EnterTaskError)?,
-        )?;
     }
-    Ok(())
+
+    execution.finished(&report);
+    Ok(report)
 }
 
+/// Same as [`run`] but runs up to `max_concurrency` tasks at the same time
 pub async fn run_parallel(
     workspace: &Workspace,
     current: &Taskfile,
     req: &TaskInvocation<TaskRef>,
     run_manager: impl RunManager + 'static,
     max_concurrency: usize,
-) -> Result<(), RunError> {
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-
-    ctrlc::set_handler(move || {
-        r.store(false, std::sync::atomic::Ordering::SeqCst);
-    }).unwrap();
-
+    options: &RunOptions,
+    interrupt: &Interrupt,
+) -> Result<RunReport, RunError> {
     let (deps_graph, instantiations) = build_dependency_graph(workspace, current, req)?;
 
     let sorted = topological_sort(&deps_graph)?;
 
-    let trigger_checker = Arc::new(Mutex::new(NaiveTriggerChecker::default()));
+    // shared by the tasks, each one hashes its files without blocking the others
+    let trigger_checker = PersistentTriggerChecker::load(current.state_dir());
 
     let execution = run_manager.begin(sorted.iter().rev()).map_err(RunError::BeginTaskError)?;
     let execution = Arc::new(execution);
 
+    // start the longest chains first, as hinted by the task or measured on the previous runs
+    let weights = instantiations
+        .iter()
+        .filter_map(|(invocation, task)| {
+            let expected = match task.body.weight {
+                Some(seconds) => Duration::from_secs_f64(seconds),
+                None => trigger_checker.expected_duration(invocation)?,
+            };
+            Some((invocation.clone(), expected.as_millis() as u64))
+        })
+        .collect();
+
+    // every task needs one cpu unless stated otherwise, and holds one unit of each of its locks
+    let mut limits = current.limits.clone();
+    limits.extend(options.limits.clone());
+    limits.entry(CPU.to_string()).or_insert(max_concurrency as u64);
+    let resources = instantiations
+        .iter()
+        .map(|(invocation, task)| {
+            let mut needs = task.body.resources.clone();
+            needs.entry(CPU.to_string()).or_insert(1);
+            for lock in &task.body.locks {
+                needs.entry(lock.clone()).or_insert(1);
+                limits.entry(lock.clone()).or_insert(1);
+            }
+            (invocation.clone(), needs)
+        })
+        .collect();
+    // interactive tasks hold the whole terminal, no other task runs meanwhile
+    let exclusive = instantiations
+        .iter()
+        .filter(|(_, task)| task.body.interactive)
+        .map(|(invocation, _)| invocation.clone())
+        .collect();
+
     let instantiations = Arc::new(instantiations);
 
+    // reports of the tasks that were started
+    let reports = Arc::new(Mutex::new(HashMap::new()));
+
     let r = execute_tasks_concurrently(
-        max_concurrency, // TODO maybe physical instead?
+        SchedulerConfig {
+            max_concurrency, // TODO maybe physical instead?
+            keep_going: options.keep_going,
+            weights,
+            resources,
+            limits: limits.into_iter().collect(),
+            exclusive,
+            jobserver: options.jobserver.clone(),
+        },
         sorted.iter().rev().cloned(), // FIXME stupid af
         deps_graph,
-        move || running.load(std::sync::atomic::Ordering::SeqCst),
-        move|invocation| {
-            let instantiations = instantiations.clone();
-            let invocation  = invocation.clone(); // TODO avoid clone
-            let current = current.clone(); // TODO avoid clone
-            let mut trigger_checker = trigger_checker.clone();
+        {
+            let interrupt = interrupt.clone();
+            move || !interrupt.is_triggered()
+        },
+        {
             let execution = execution.clone();
-            async move {
-                let r = tokio::task::spawn_blocking(move || -> Result<(), RunError> {
-                    let cx = execution.enter_task(&invocation).map_err(RunError::EnterTaskError);
-                    let r = maybe_run_single_task(
-                        &current,
-                        &*instantiations,
-                        &invocation,
-                        &mut trigger_checker,
-                        cx?,
-                    )?;
-                    Ok(r)
-                }).await.unwrap();
-                Ok(r?)
+            let reports = reports.clone();
+            let options = options.clone();
+            move |invocation| {
+                let instantiations = instantiations.clone();
+                let invocation  = invocation.clone(); // TODO avoid clone
+                let current = current.clone(); // TODO avoid clone
+                let mut trigger_checker = trigger_checker.clone();
+                let execution = execution.clone();
+                let interrupt = interrupt.clone();
+                let reports = reports.clone();
+                let options = options.clone();
+                async move {
+                    let mut report = TaskReport::new(&invocation);
+                    run_task(&current, &instantiations, &*execution, &mut trigger_checker, &interrupt, &options, &mut report).await;
+                    let failed = !report.status.is_success();
+                    reports.lock().unwrap().insert(invocation.clone(), report);
+                    if failed {
+                        // the error is kept in the report, the scheduler only needs to know the task failed
+                        anyhow::bail!("Task {} failed", invocation.r#ref.display_absolute());
+                    }
+                    Ok(())
+                }
             }
         },
     ).await;
 
-    r.map_err(|e| RunError::ExecutionError(TaskExecutionError::Other(e)))
+    let mut reports = std::mem::take(&mut *reports.lock().unwrap());
+    let report = RunReport {
+        tasks: sorted
+            .iter()
+            .rev()
+            .map(|invocation| reports.remove(invocation).unwrap_or_else(|| TaskReport::new(invocation)))
+            .collect(),
+        interrupted: r.is_err() && interrupt.is_triggered(),
+    };
+
+    execution.finished(&report);
+
+    match r {
+        // not caused by a task failure
+        Err(e) if !report.interrupted && report.with_status(TaskStatus::Failed).next().is_none() => {
+            Err(RunError::ExecutionError(TaskExecutionError::Other(e)))
+        },
+        _ => Ok(report),
+    }
+}
+
+/// Runs a single task, recording what happened in `report`
+///
+/// When tracing, the task is recorded on the first free lane of the trace.
+/// With a jobserver, its steps share it with the other tasks. The output of
+/// its steps is also written to its log.
+async fn run_task<E: RunExecution, T: TaskTriggerChecker>(
+    current: &Taskfile,
+    instantiations: &HashMap<ResolvedTaskInvocation, InstantiatedTask>,
+    execution: &E,
+    trigger_checker: &mut T,
+    interrupt: &Interrupt,
+    options: &RunOptions,
+    report: &mut TaskReport,
+) {
+    let invocation = &report.invocation.clone(); // TODO avoid clone
+    let run = async {
+        let mut span = trace::span(|| format!("{} {}", invocation.r#ref.name, display_args(invocation)).trim_end().to_string(), "task");
+        span.arg("taskfile", || invocation.r#ref.taskfile.to_string().into());
+        let Some(task) = instantiations.get(invocation) else {
+            report.finish(Err(TaskExecutionError::TaskNotFound(invocation.clone()).into()));
+            return;
+        };
+        match execution.enter_task(invocation, task) {
+            Ok(mut cx) => {
+                let r = maybe_run_single_task(
+                    current,
+                    instantiations,
+                    invocation,
+                    trigger_checker,
+                    &mut cx,
+                    interrupt,
+                    report,
+                ).await;
+                report.finish(r.map_err(RunError::from));
+                logs::task_finished(report);
+                cx.finished(report);
+            },
+            Err(e) => report.finish(Err(RunError::EnterTaskError(e))),
+        }
+        span.arg("status", || report.status.to_string().into());
+    };
+
+    let logged = async {
+        match &options.logs {
+            Some(logs) => logs.with_task_log(invocation, run).await,
+            None => run.await,
+        }
+    };
+
+    let traced = async {
+        match &options.trace {
+            Some(trace) => trace.on_lane(logged).await,
+            None => logged.await,
+        }
+    };
+
+    // the token of the task is taken by the scheduler
+    match &options.jobserver {
+        Some(jobserver) => jobserver.scope(traced).await,
+        None => traced.await,
+    }
+}
+
+/// Walks the tasks in execution order and decides which ones would run, without running anything
+pub fn explain(
+    workspace: &Workspace,
+    current: &Taskfile,
+    req: &TaskInvocation<TaskRef>,
+) -> Result<Vec<(ResolvedTaskInvocation, TriggerDecision)>, RunError> {
+    let (deps_graph, instantiations) = build_dependency_graph(workspace, current, req)?;
+
+    let sorted = topological_sort(&deps_graph)?;
+
+    let mut trigger_checker = PersistentTriggerChecker::load(current.state_dir());
+
+    // Since nothing actually runs, the outputs of the tasks that would run are not
+    // updated. We keep track of them so that the tasks using them are triggered too.
+    let mut regenerated = HashSet::new();
+
+    let mut decisions = Vec::new();
+    for invocation in sorted.iter().rev() {
+        let task = instantiations
+            .get(invocation)
+            .ok_or_else(|| TaskExecutionError::TaskNotFound(invocation.clone()))?;
+
+        let regenerated_source = task.resolve_sources().iter().find(|source| regenerated.contains(*source)).cloned();
+        let decision = match regenerated_source {
+            Some(source) if !task.body.steps.is_empty() => TriggerDecision::Run(RunReason::SourceRegenerated(source)),
+            _ => {
+                let mut context = trigger_checker.new_task_context(invocation);
+                trigger_checker
+                    .should_run(task, &mut context)
+                    .map_err(|e| TaskExecutionError::ShouldRunCheckError(e.into()))?
+            }
+        };
+
+        if decision.should_run() {
+            regenerated.extend(task.resolve_outputs().map(|output| output.as_ref().to_path_buf()));
+        }
+
+        decisions.push((invocation.clone(), decision));
+    }
+
+    Ok(decisions)
 }
 
-pub fn clean(
+pub async fn clean(
     workspace: &Workspace,
     current: &Taskfile,
     req: &TaskInvocation<TaskRef>,
@@ -132,14 +358,14 @@ pub fn clean(
     let sorted = topological_sort(&deps_graph)?;
 
     for invocation in sorted.iter() {
-        clean_single_task(current, &instantiations, invocation, |output| {
-            println!("{}", output);
-        })?;
+        clean_single_task(current, &instantiations, invocation, &Interrupt::new(), |output| {
+            output.stream.println(output.text());
+        }).await?;
     }
     Ok(())
 }
 
-pub fn clean_only(
+pub async fn clean_only(
     workspace: &Workspace,
     current: &Taskfile,
     req: &TaskInvocation<TaskRef>,
@@ -149,13 +375,13 @@ pub fn clean_only(
         .1
         .instantiate(&req.args, &current.env)?; // TODO error handling
 
-    clean_instantiated_task(current, &task, |output| {
-        println!("{}", output);
-    })?;
+    clean_instantiated_task(current, &task, &Interrupt::new(), |output| {
+        output.stream.println(output.text());
+    }).await?;
     Ok(())
 }
 
-fn display_args(invocation: &ResolvedTaskInvocation) -> String {
+pub(crate) fn display_args(invocation: &ResolvedTaskInvocation) -> String {
     invocation
         .args
         .iter()
@@ -5,9 +5,20 @@ use linked_hash_set::LinkedHashSet;
 
 use crate::task::{InstantiatedTask, InstantiationError, ResolvedTaskInvocation, TaskInvocation, TaskRef, Taskfile, TaskfileId, Workspace};
 
+pub mod export;
 pub mod naive;
 pub mod topological_sort;
 
+/// Dependency graph that keeps track of where the edges come from
+#[derive(Debug, Clone)]
+pub struct AnnotatedDependencyGraph {
+    /// Each invocation with the invocations it has to wait for
+    pub graph: LinkedHashMap<ResolvedTaskInvocation, LinkedHashSet<ResolvedTaskInvocation>>,
+    pub instantiations: HashMap<ResolvedTaskInvocation, InstantiatedTask>,
+    /// Edges `(task, other)` that only exist because of an `after` constraint
+    pub after_edges: HashSet<(ResolvedTaskInvocation, ResolvedTaskInvocation)>,
+}
+
 pub fn build_dependency_graph(
     workspace: &Workspace,
     current: &Taskfile,
@@ -16,6 +27,15 @@ pub fn build_dependency_graph(
     LinkedHashMap<ResolvedTaskInvocation, LinkedHashSet<ResolvedTaskInvocation>>,
     HashMap<ResolvedTaskInvocation, InstantiatedTask>,
 ), DependencyGraphConstructionError> {
+    let AnnotatedDependencyGraph { graph, instantiations, .. } = build_annotated_dependency_graph(workspace, current, invocation)?;
+    Ok((graph, instantiations))
+}
+
+pub fn build_annotated_dependency_graph(
+    workspace: &Workspace,
+    current: &Taskfile,
+    invocation: &TaskInvocation<TaskRef>,
+) -> Result<AnnotatedDependencyGraph, DependencyGraphConstructionError> {
     let mut queue: VecDeque<ResolvedTaskInvocation> = VecDeque::new();
 
     // Initialize the queue with the requested task invocation
@@ -33,6 +53,8 @@ pub fn build_dependency_graph(
 
     let mut graph: LinkedHashMap<ResolvedTaskInvocation, LinkedHashSet<ResolvedTaskInvocation>> = LinkedHashMap::new();
     let mut instantiations = HashMap::new();
+    let mut dep_edges = HashSet::new();
+    let mut after_edges = HashSet::new();
 
     while let Some(invocation) = queue.pop_front() {
         if visited.contains(&invocation) {
@@ -63,6 +85,7 @@ pub fn build_dependency_graph(
                 }
             }
             node.insert(dep_invocation.clone());
+            dep_edges.insert((invocation.clone(), dep_invocation.clone()));
             if !visited.contains(&dep_invocation) {
                 queue.push_back(dep_invocation.clone());
             }
@@ -90,26 +113,28 @@ pub fn build_dependency_graph(
                     .or_insert_with(LinkedHashSet::new);
 
                 node.insert(referenced.clone());
+                after_edges.insert((dep_invocation.clone(), referenced.clone()));
             }
         }
     }
 
-    // ! dump dependency graph
+    // an `after` constraint might duplicate an actual dependency, in which case
+    // the edge is a dependency edge
+    after_edges.retain(|edge| !dep_edges.contains(edge));
+
     // TODO cycle detection at this level might be simpler and more informative,
     // or maybe only for an LSP
-    // for (key, value) in &graph {
-    //     println!("{}", key.r#ref.display_absolute());
-    //     for v in value {
-    //         println!("  -> {}", v.r#ref.display_absolute());
-    //     }
-    // }
 
     // TODO we should ad an optional (because it might be expensive) missing constraint check here (after full graph creation though).
     // For example, if in the graph there are A and B which are not constrained, but one uses the
     // output of the other, we should issue a warning. Or possibly warning+auto-constraint addition,
     // but this might be surprising and expensive. Note: special care with sub-dirs!
 
-    Ok((graph, instantiations))
+    Ok(AnnotatedDependencyGraph {
+        graph,
+        instantiations,
+        after_edges,
+    })
 }
 
 #[derive(Debug, thiserror::Error)]
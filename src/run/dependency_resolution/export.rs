@@ -0,0 +1,222 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, fmt::Write, path::Path};
+
+use serde::Serialize;
+use serde_json::Value as Json;
+
+use crate::{run::dependency_resolution::AnnotatedDependencyGraph, task::ResolvedTaskInvocation};
+
+/// Renders the graph in the Graphviz DOT format
+///
+/// Edges go from a task to the tasks it waits for, `after` constraints are dashed.
+pub fn to_dot(graph: &AnnotatedDependencyGraph, cwd: &Path) -> String {
+    let ids = node_ids(graph);
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+
+    let mut out = String::new();
+    writeln!(out, "digraph tasks {{").unwrap();
+    writeln!(out, "    node [shape=box];").unwrap();
+    for (invocation, id) in graph.graph.keys().map(|i| (i, ids[i])) {
+        let label = node_label(invocation, cwd).iter().map(|l| escape(l)).collect::<Vec<_>>().join("\\n");
+        writeln!(out, "    n{id} [label=\"{label}\"];").unwrap();
+    }
+    for (from, to, after) in edges(graph) {
+        if after {
+            writeln!(out, "    n{} -> n{} [style=dashed, label=\"after\"];", ids[from], ids[to]).unwrap();
+        } else {
+            writeln!(out, "    n{} -> n{};", ids[from], ids[to]).unwrap();
+        }
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+/// Renders the graph as a Mermaid flowchart
+///
+/// Edges go from a task to the tasks it waits for, `after` constraints are dotted.
+pub fn to_mermaid(graph: &AnnotatedDependencyGraph, cwd: &Path) -> String {
+    let ids = node_ids(graph);
+    let escape = |s: &str| s.replace('"', "#quot;");
+
+    let mut out = String::new();
+    writeln!(out, "flowchart TD").unwrap();
+    for (invocation, id) in graph.graph.keys().map(|i| (i, ids[i])) {
+        let label = node_label(invocation, cwd).join("<br/>");
+        writeln!(out, "    n{id}[\"{}\"]", escape(&label)).unwrap();
+    }
+    for (from, to, after) in edges(graph) {
+        if after {
+            writeln!(out, "    n{} -.->|after| n{}", ids[from], ids[to]).unwrap();
+        } else {
+            writeln!(out, "    n{} --> n{}", ids[from], ids[to]).unwrap();
+        }
+    }
+    out
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonGraph {
+    pub nodes: Vec<JsonNode>,
+    pub edges: Vec<JsonEdge>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonNode {
+    pub id: usize,
+    pub taskfile: String,
+    pub task: String,
+    pub args: BTreeMap<String, Json>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonEdge {
+    /// The task that waits
+    pub from: usize,
+    /// The task that is waited for
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EdgeKind {
+    Dependency,
+    After,
+}
+
+/// Builds a machine-readable representation of the graph
+pub fn to_json(graph: &AnnotatedDependencyGraph) -> JsonGraph {
+    let ids = node_ids(graph);
+    JsonGraph {
+        nodes: graph
+            .graph
+            .keys()
+            .map(|invocation| JsonNode {
+                id: ids[invocation],
+                taskfile: invocation.r#ref.taskfile.to_string(),
+                task: invocation.r#ref.name.clone(),
+                args: invocation.args.clone(),
+            })
+            .collect(),
+        edges: edges(graph)
+            .map(|(from, to, after)| JsonEdge {
+                from: ids[from],
+                to: ids[to],
+                kind: if after { EdgeKind::After } else { EdgeKind::Dependency },
+            })
+            .collect(),
+    }
+}
+
+fn node_ids(graph: &AnnotatedDependencyGraph) -> HashMap<&ResolvedTaskInvocation, usize> {
+    graph.graph.keys().enumerate().map(|(i, invocation)| (invocation, i)).collect()
+}
+
+/// Iterates over the edges as `(from, to, is_after)`
+fn edges(graph: &AnnotatedDependencyGraph) -> impl Iterator<Item = (&ResolvedTaskInvocation, &ResolvedTaskInvocation, bool)> {
+    // borrowed, so that looking up an edge doesn't clone the invocations
+    let after_edges = graph.after_edges.iter().map(|(from, to)| (from, to)).collect::<HashSet<_>>();
+    graph
+        .graph
+        .iter()
+        .flat_map(|(from, deps)| deps.iter().map(move |to| (from, to)))
+        .map(move |(from, to)| (from, to, after_edges.contains(&(from, to))))
+}
+
+/// The task name followed by one line per argument
+fn node_label(invocation: &ResolvedTaskInvocation, cwd: &Path) -> Vec<String> {
+    std::iter::once(invocation.r#ref.display_relative(cwd).to_string())
+        .chain(invocation.args.iter().map(|(k, v)| format!("{k}={v}")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use linked_hash_map::LinkedHashMap;
+    use linked_hash_set::LinkedHashSet;
+    use serde_json::json;
+
+    use crate::task::{ResolvedRef, TaskInvocation, TaskfileId};
+
+    use super::*;
+
+    fn invocation(taskfile: &str, name: &str, args: &[(&str, Json)]) -> ResolvedTaskInvocation {
+        TaskInvocation {
+            r#ref: ResolvedRef {
+                taskfile: TaskfileId::from_path(taskfile),
+                name: name.to_string(),
+            },
+            args: args.iter().map(|(k, v)| (k.to_string(), v.clone())).collect(),
+        }
+    }
+
+    /// `build` needs `compile` and `lib:gen` and runs after `test`, `compile` needs `lib:gen`
+    fn graph() -> AnnotatedDependencyGraph {
+        let build = invocation("/ws/tasks.yaml", "build", &[]);
+        let compile = invocation("/ws/tasks.yaml", "compile", &[("files", json!(["a.c", "b.c"])), ("mode", json!("release"))]);
+        let generate = invocation("/ws/lib/tasks.yaml", "gen", &[]);
+        let test = invocation("/ws/tasks.yaml", "test", &[]);
+
+        let mut graph = LinkedHashMap::new();
+        graph.insert(build.clone(), LinkedHashSet::from_iter([compile.clone(), generate.clone(), test.clone()]));
+        graph.insert(compile.clone(), LinkedHashSet::from_iter([generate.clone()]));
+        graph.insert(generate, LinkedHashSet::new());
+        graph.insert(test.clone(), LinkedHashSet::new());
+
+        AnnotatedDependencyGraph {
+            graph,
+            instantiations: HashMap::new(),
+            after_edges: HashSet::from([(build, test)]),
+        }
+    }
+
+    #[test]
+    fn dot() {
+        let expected = r#"digraph tasks {
+    node [shape=box];
+    n0 [label="build"];
+    n1 [label="compile\nfiles=[\"a.c\",\"b.c\"]\nmode=\"release\""];
+    n2 [label="lib:gen"];
+    n3 [label="test"];
+    n0 -> n1;
+    n0 -> n2;
+    n0 -> n3 [style=dashed, label="after"];
+    n1 -> n2;
+}
+"#;
+        assert_eq!(to_dot(&graph(), "/ws".as_ref()), expected);
+    }
+
+    #[test]
+    fn mermaid() {
+        let expected = r#"flowchart TD
+    n0["build"]
+    n1["compile<br/>files=[#quot;a.c#quot;,#quot;b.c#quot;]<br/>mode=#quot;release#quot;"]
+    n2["lib:gen"]
+    n3["test"]
+    n0 --> n1
+    n0 --> n2
+    n0 -.->|after| n3
+    n1 --> n2
+"#;
+        assert_eq!(to_mermaid(&graph(), "/ws".as_ref()), expected);
+    }
+
+    #[test]
+    fn json() {
+        let expected = json!({
+            "nodes": [
+                { "id": 0, "taskfile": "/ws/tasks.yaml", "task": "build", "args": {} },
+                { "id": 1, "taskfile": "/ws/tasks.yaml", "task": "compile", "args": { "files": ["a.c", "b.c"], "mode": "release" } },
+                { "id": 2, "taskfile": "/ws/lib/tasks.yaml", "task": "gen", "args": {} },
+                { "id": 3, "taskfile": "/ws/tasks.yaml", "task": "test", "args": {} },
+            ],
+            "edges": [
+                { "from": 0, "to": 1, "kind": "dependency" },
+                { "from": 0, "to": 2, "kind": "dependency" },
+                { "from": 0, "to": 3, "kind": "after" },
+                { "from": 1, "to": 2, "kind": "dependency" },
+            ],
+        });
+        assert_eq!(serde_json::to_value(to_json(&graph())).unwrap(), expected);
+    }
+}
@@ -1,12 +1,14 @@
-use std::{borrow::Borrow, collections::{BTreeMap, HashMap}, path::Path};
+use std::{borrow::Cow, collections::{BTreeMap, HashMap}, fmt::Display, future::Future, io::Write, path::Path, process::ExitStatus, time::SystemTime};
 
 use colored::Colorize;
 use pathdiff::diff_paths;
+use serde::Serialize;
 use serde_json::Value as Json;
+use tokio::runtime::RuntimeFlavor;
 
 use crate::{
     command::Command,
-    run::{execution::{naive::NaiveExecutor, triggers::TaskTriggerChecker}, run_manager::TaskExecutionContext},
+    run::{execution::{naive::{NaiveExecutor, DEFAULT_GRACE_PERIOD}, triggers::TaskTriggerChecker}, interrupt::Interrupt, report::TaskReport, run_manager::TaskExecutionContext, trace},
     task::{InstantiatedTask, OutputPath, ResolvedTaskInvocation, Taskfile},
 };
 
@@ -14,13 +16,109 @@ pub mod naive;
 pub mod triggers;
 pub mod scheduler;
 
-pub trait CommandExecutor {
-    fn execute<C: Borrow<Command>>(
+pub trait CommandExecutor: Send {
+    /// Executes the commands in order, stopping early if `interrupt` is triggered
+    ///
+    /// The returned future is `Send`, so tasks running it can be spawned on any runtime.
+    fn execute(
         &mut self,
-        pwd: impl AsRef<Path>,
+        pwd: &Path,
         env: &BTreeMap<String, Json>,
-        commands: impl IntoIterator<Item = C>,
-    ) -> anyhow::Result<()>; // TODO error type
+        commands: &[Command],
+        interrupt: &Interrupt,
+    ) -> impl Future<Output = Result<(), CommandExecutionError>> + Send;
+}
+
+/// The pipe a step wrote its output to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    /// Prints `line` to the same stream of birb
+    pub fn println(self, line: impl Display) {
+        let result = match self {
+            OutputStream::Stdout => writeln!(std::io::stdout().lock(), "{line}"),
+            OutputStream::Stderr => writeln!(std::io::stderr().lock(), "{line}"),
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to print step output: {e}");
+        }
+    }
+}
+
+/// A line of output of a step, without its line ending
+#[derive(Debug, Clone, Copy)]
+pub struct OutputLine<'a> {
+    pub stream: OutputStream,
+    /// The raw bytes, not necessarily UTF-8
+    pub bytes: &'a [u8],
+}
+
+impl OutputLine<'_> {
+    /// The line as text, invalid UTF-8 is replaced with `U+FFFD`
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.bytes)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CommandExecutionError {
+    #[error("Execution interrupted")]
+    Interrupted,
+    #[error("Failed to execute command '{0}': {1}")]
+    SpawnError(String, std::io::Error),
+    #[error("{0}")]
+    StepFailed(StepFailure),
+    #[error("{0}")]
+    Other(anyhow::Error),
+}
+
+/// A step that exited unsuccessfully
+#[derive(Debug)]
+pub struct StepFailure {
+    /// Index of the step in the task, starting from 0
+    pub step: usize,
+    /// The rendered command
+    pub command: String,
+    pub status: ExitStatus,
+    /// The last lines printed by the step
+    pub output_tail: Vec<String>,
+}
+
+impl StepFailure {
+    /// The exit code birb should exit with because of this failure
+    pub fn exit_code(&self) -> u8 {
+        #[cfg(unix)]
+        if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&self.status) {
+            return 128u8.saturating_add(signal as u8);
+        }
+        match self.status.code() {
+            Some(code @ 1..=255) => code as u8,
+            _ => 1,
+        }
+    }
+
+    /// Describes how the step exited, e.g. `exit code 2`
+    pub fn status_description(&self) -> String {
+        #[cfg(unix)]
+        if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&self.status) {
+            return format!("killed by signal {signal}");
+        }
+        match self.status.code() {
+            Some(code) => format!("exit code {code}"),
+            None => self.status.to_string(),
+        }
+    }
+}
+
+impl Display for StepFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "step {} failed, {}", self.step + 1, self.status_description())
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -33,49 +131,89 @@ pub enum TaskExecutionError {
     ShouldRunCheckError(anyhow::Error),
     #[error("Output check failed: {0}")]
     OutputCheckError(anyhow::Error),
+    #[error("Task {} failed: {failure}", .invocation.r#ref.display_absolute())]
+    StepFailed {
+        invocation: ResolvedTaskInvocation,
+        failure: StepFailure,
+    },
+    #[error("Task {} interrupted", .0.r#ref.display_absolute())]
+    Interrupted(ResolvedTaskInvocation),
     #[error("Command execution failed: {0}")]
-    CommandExecutorError(anyhow::Error), // TODO better error type
+    CommandExecutorError(CommandExecutionError),
     #[error("Other")]
     Other(anyhow::Error), // TODO remove this
 }
 
-pub fn maybe_run_single_task<T: TaskTriggerChecker, C: TaskExecutionContext>(
+/// Runs the task if the trigger checker decides so
+///
+/// The start time and the trigger decision are recorded in `report`, its status
+/// is left to the caller.
+pub async fn maybe_run_single_task<T: TaskTriggerChecker, C: TaskExecutionContext>(
     current: &Taskfile,
     tasks: &HashMap<ResolvedTaskInvocation, InstantiatedTask>,
     invocation: &ResolvedTaskInvocation,
     trigger_checker: &mut T,
-    mut execution_context: C,
+    execution_context: &mut C,
+    interrupt: &Interrupt,
+    report: &mut TaskReport,
 ) -> Result<(), TaskExecutionError> {
+    report.start = Some(SystemTime::now());
+
     let task = tasks
         .get(&invocation)
         .ok_or(TaskExecutionError::TaskNotFound(invocation.clone()))?;
 
-    let mut context = trigger_checker.new_task_context();
+    let mut context = trigger_checker.new_task_context(invocation);
 
     log::trace!("Checking if task {:?} should run", invocation);
-    let should_run = trigger_checker.should_run(task, &mut context)
+    let span = trace::span(|| "check triggers".to_string(), "trigger");
+    let decision = blocking(|| trigger_checker.should_run(task, &mut context))
         .map_err(|e| TaskExecutionError::ShouldRunCheckError(e.into()))?;
-    log::trace!("Task {:?} should run: {}", invocation, should_run);
+    drop(span);
+    log::trace!("Task {:?} trigger decision: {}", invocation, decision);
+    let should_run = decision.should_run();
+    report.decision = Some(decision);
 
     if should_run {
         let mut env = current.env.clone();
         env.extend(task.body.env.clone());
-        execution_context.run().execute(&task.body.workdir, &env, &task.body.steps).map_err(TaskExecutionError::CommandExecutorError)?;
+        let _span = trace::span(|| "run steps".to_string(), "steps");
+        execution_context.run().execute(&task.body.workdir, &env, &task.body.steps, interrupt).await.map_err(|e| match e {
+            CommandExecutionError::StepFailed(failure) => TaskExecutionError::StepFailed {
+                invocation: invocation.clone(),
+                failure,
+            },
+            CommandExecutionError::Interrupted => TaskExecutionError::Interrupted(invocation.clone()),
+            e => TaskExecutionError::CommandExecutorError(e),
+        })?;
     } else {
         execution_context.up_to_date();
     }
 
-    trigger_checker.check_outputs(task, &mut context, should_run)
+    let _span = trace::span(|| "check outputs".to_string(), "trigger");
+    blocking(|| trigger_checker.check_outputs(task, &mut context, should_run))
         .map_err(|e| TaskExecutionError::OutputCheckError(e.into()))?;
 
     Ok(())
 }
 
-pub fn clean_single_task(
+/// Runs blocking work, like hashing and walking the sources, without stalling the other tasks
+///
+/// On a multi-thread runtime the worker hands its other tasks over to another
+/// thread first, on other runtimes `f` just runs inline.
+fn blocking<R>(f: impl FnOnce() -> R) -> R {
+    match tokio::runtime::Handle::try_current().map(|handle| handle.runtime_flavor()) {
+        Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+pub async fn clean_single_task(
     tasks: &Taskfile,
     instantiated_tasks: &HashMap<ResolvedTaskInvocation, InstantiatedTask>,
     invocation: &ResolvedTaskInvocation,
-    output_handler: impl FnMut(&str),
+    interrupt: &Interrupt,
+    output_handler: impl FnMut(OutputLine) + Send,
 ) -> Result<(), TaskExecutionError> {
     let task = instantiated_tasks
         .get(&invocation)
@@ -85,24 +223,28 @@ pub fn clean_single_task(
 
     println!("    {} cleaning...", invocation.r#ref.display_relative(&cwd).to_string().bold().green());
 
-    clean_instantiated_task(tasks, task, output_handler)?;
+    clean_instantiated_task(tasks, task, interrupt, output_handler).await?;
 
     Ok(())
 }
 
-pub fn clean_instantiated_task(
+pub async fn clean_instantiated_task(
     tasks: &Taskfile,
     task: &InstantiatedTask,
-    mut output_handler: impl FnMut(&str),
+    interrupt: &Interrupt,
+    mut output_handler: impl FnMut(OutputLine) + Send,
 ) -> Result<(), TaskExecutionError> {
     if let Some(clean_steps) = &task.body.clean {
         // HACK temporary solution
         let mut executor = NaiveExecutor {
             output_handler: &mut output_handler,
+            grace_period: DEFAULT_GRACE_PERIOD,
+            interactive: task.body.interactive,
+            tty: task.body.tty,
         };
         let mut env = tasks.env.clone();
         env.extend(task.body.env.clone());
-        executor.execute(&task.body.workdir, &env, clean_steps).map_err(TaskExecutionError::Other)?;
+        executor.execute(&task.body.workdir, &env, clean_steps, interrupt).await.map_err(TaskExecutionError::CommandExecutorError)?;
     }
 
     for o in task.resolve_outputs() {
@@ -131,6 +273,8 @@ pub fn clean_instantiated_task(
             } else {
                 println!("{}\t{}", rel_path.display(), "NOT FOUND".bright_black());
             },
+            // a glob that matched no file
+            OutputPath::Glob(_) => println!("{}\t{}", rel_path.display(), "NOT FOUND".bright_black()),
         }
     }
 
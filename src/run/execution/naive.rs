@@ -1,28 +1,56 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::io::Write;
-use std::{borrow::Borrow, io::BufRead, path::Path};
-use std::sync::mpsc;
-use std::thread;
+use std::path::Path;
+use std::time::Duration;
 
 use tempfile::NamedTempFile;
 use serde_json::Value as Json;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Child;
+use tokio::time::Instant;
 
-use crate::{command::Command, run::execution::CommandExecutor};
+use crate::{command::Command, run::{execution::{CommandExecutionError, CommandExecutor, OutputLine, OutputStream, StepFailure}, interrupt::Interrupt, jobserver, logs}};
 
-pub struct NaiveExecutor<F: FnMut(&str)> {
+#[cfg(unix)]
+mod pty;
+
+/// Number of output lines kept to be reported when a step fails
+const OUTPUT_TAIL_LINES: usize = 10;
+
+/// Time given to interrupted steps to exit before they are killed
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// How long we keep reading the output after a step exited, background
+/// processes it started may keep the pipes open
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Runs the steps as Tokio child processes, their output is read asynchronously
+pub struct NaiveExecutor<F: FnMut(OutputLine)> {
     pub output_handler: F,
+    /// How long an interrupted step can take to exit before it is killed
+    pub grace_period: Duration,
+    /// The steps use the terminal instead of pipes, see [`TaskBody::interactive`](crate::task::TaskBody::interactive)
+    pub interactive: bool,
+    /// The steps write to pseudo-terminals instead of pipes, see [`TaskBody::tty`](crate::task::TaskBody::tty)
+    ///
+    /// Only on Unix, ignored for interactive steps.
+    pub tty: bool,
 }
 
-impl<F: FnMut(&str)> CommandExecutor for NaiveExecutor<F> {
-    fn execute<C: Borrow<Command>>(
+impl<F: FnMut(OutputLine) + Send> CommandExecutor for NaiveExecutor<F> {
+    async fn execute(
         &mut self,
-        pwd: impl AsRef<Path>,
+        pwd: &Path,
         env: &BTreeMap<String, Json>,
-        commands: impl IntoIterator<Item = C>,
-    ) -> anyhow::Result<()> {
-        for command in commands {
-            match command.borrow() {
-                Command::Shell(cmd) => Self::exec_shell(&pwd, env, &cmd, &mut self.output_handler)?,
+        commands: &[Command],
+        interrupt: &Interrupt,
+    ) -> Result<(), CommandExecutionError> {
+        for (step, command) in commands.iter().enumerate() {
+            if interrupt.is_triggered() {
+                return Err(CommandExecutionError::Interrupted);
+            }
+            match command {
+                Command::Shell(cmd) => self.exec_shell(pwd, env, step, cmd, interrupt).await?,
             }
         }
 
@@ -30,8 +58,17 @@ impl<F: FnMut(&str)> CommandExecutor for NaiveExecutor<F> {
     }
 }
 
-impl<F: FnMut(&str)> NaiveExecutor<F> {
-    fn exec_shell(pwd: impl AsRef<Path>, env: &BTreeMap<String, Json>, cmd: &str, mut output_handler: impl FnMut(&str)) -> anyhow::Result<()> {
+impl<F: FnMut(OutputLine) + Send> NaiveExecutor<F> {
+    async fn exec_shell(
+        &mut self,
+        pwd: &Path,
+        env: &BTreeMap<String, Json>,
+        step: usize,
+        cmd: &str,
+        interrupt: &Interrupt,
+    ) -> Result<(), CommandExecutionError> {
+        let Self { output_handler, grace_period, interactive, tty } = self;
+        let (grace_period, interactive, tty) = (*grace_period, *interactive, cfg!(unix) && *tty && !*interactive);
         // try to find the shebang
         let shebang = cmd.lines().next().filter(|line| line.starts_with("#!")).map(|line| line.to_string());
         let mut script: NamedTempFile;
@@ -48,97 +85,398 @@ impl<F: FnMut(&str)> NaiveExecutor<F> {
             ("sh".to_string(), vec!["-c".to_string(), cmd.to_string()]) // TODO avoid useless string clone, use cow or something
         };
 
+        logs::step_started(step, cmd);
+
         let mut command = std::process::Command::new(&program);
-        command.args(&args)
-            .current_dir(&pwd)
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .stdin(std::process::Stdio::null());
+        command.args(&args).current_dir(pwd);
+        // read instead of the pipes
+        let mut stdout_tty: Option<Box<dyn AsyncRead + Send + Unpin>> = None;
+        let mut stderr_tty: Option<Box<dyn AsyncRead + Send + Unpin>> = None;
+        if interactive {
+            command
+                .stdout(std::process::Stdio::inherit())
+                .stderr(std::process::Stdio::inherit())
+                .stdin(std::process::Stdio::inherit());
+        } else if tty {
+            // one pseudo-terminal per stream, so the lines keep their stream
+            #[cfg(unix)]
+            {
+                let open = || pty::Pty::open().map_err(|e| CommandExecutionError::SpawnError(cmd.to_string(), e));
+                let (out, err) = (open()?, open()?);
+                command
+                    .stdout(std::process::Stdio::from(out.slave))
+                    .stderr(std::process::Stdio::from(err.slave))
+                    .stdin(std::process::Stdio::null());
+                stdout_tty = Some(Box::new(out.master));
+                stderr_tty = Some(Box::new(err.master));
+            }
+        } else {
+            command
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped())
+                .stdin(std::process::Stdio::null());
+        }
 
         for (key, value) in env {
             command.env(key, value.as_str().unwrap_or(&value.to_string()));
         }
 
+        // nested builds share our jobserver instead of starting their own jobs
+        jobserver::configure(&mut command);
+
         // Set process group on Unix systems so we can send signals to the whole group
         #[cfg(unix)]
+        let interactive_terminal = interactive && owns_terminal();
+        #[cfg(unix)]
         {
-            //use std::os::unix::process::CommandExt;
-            //command.process_group(0); // Create new process group
+            use std::os::unix::process::CommandExt;
+            command.process_group(0); // Create new process group
+            if interactive_terminal {
+                // the step takes the terminal before running, it could read from it before we give it
+                // SAFETY: set_foreground only calls async-signal-safe functions
+                unsafe {
+                    command.pre_exec(|| {
+                        set_foreground(libc::getpid());
+                        Ok(())
+                    })
+                };
+            }
         }
 
-        let mut child = command
+        // dropping the command closes our side of the pseudo-terminals, so their end is noticed
+        let mut child = tokio::process::Command::from(command)
+            .kill_on_drop(true)
             .spawn()
-            .map_err(|e| anyhow::anyhow!("Failed to execute command '{}': {e}", cmd))?;
-
-        let stdout = child.stdout.take().expect("Failed to capture stdout");
-        let stderr = child.stderr.take().expect("Failed to capture stderr");
-
-        let stdout_reader = std::io::BufReader::new(stdout);
-        let stderr_reader = std::io::BufReader::new(stderr);
-
-        let (tx, rx) = mpsc::channel();
-
-        // HACK custom re-implementation of read_until
-        //fn read_until<R: BufRead + ?Sized>(r: &mut R, delim: u8, buf: &mut Vec<u8>) -> std::io::Result<usize> {
-        //    let mut read = 0;
-        //    loop {
-        //        let (done, used) = {
-        //            let available = match r.fill_buf() {
-        //                Ok(n) => n,
-        //                //Err(ref e) if e.is_interrupted() => continue,
-        //                Err(e) => return Err(e),
-        //            };
-        //            //match memchr::memchr(delim, available) {
-        //            match available.iter().position(|&b| b == delim) {
-        //                Some(i) => {
-        //                    buf.extend_from_slice(&available[..=i]);
-        //                    (true, i + 1)
-        //                }
-        //                None => {
-        //                    buf.extend_from_slice(available);
-        //                    (false, available.len())
-        //                }
-        //            }
-        //        };
-        //        r.consume(used);
-        //        read += used;
-        //        if done || used == 0 {
-        //            return Ok(read);
-        //        }
-        //    }
-        //}
-
-        // Spawn thread for stdout
-        let tx_stdout = tx.clone();
-        thread::spawn(move || {
-            for line in stdout_reader.lines() {
-                if let Ok(line) = line {
-                    tx_stdout.send(line).expect("Failed to send stdout line");
-                }
+            .map_err(|e| CommandExecutionError::SpawnError(cmd.to_string(), e))?;
+        let pid = child.id().expect("Child process has no pid before being awaited");
+        #[cfg(unix)]
+        let _foreground = interactive_terminal.then(|| Foreground::give(pid as libc::pid_t));
+        // the step is killed if this future is dropped, e.g. when a watch build is cancelled
+        #[cfg(unix)]
+        let group = ProcessGroupGuard(Some(pid as libc::pid_t));
+
+        // interactive steps write to the terminal directly
+        let mut stdout = OutputLines::new(stdout_tty.or_else(|| child.stdout.take().map(|r| Box::new(r) as _)), tty);
+        let mut stderr = OutputLines::new(stderr_tty.or_else(|| child.stderr.take().map(|r| Box::new(r) as _)), tty);
+
+        let mut output_tail = VecDeque::with_capacity(OUTPUT_TAIL_LINES);
+        let mut handle_line = |stream: OutputStream, bytes: Vec<u8>| {
+            let line = OutputLine { stream, bytes: &bytes };
+            logs::output(line);
+            output_handler(line);
+            if output_tail.len() == OUTPUT_TAIL_LINES {
+                output_tail.pop_front();
             }
-        });
+            output_tail.push_back(line.text().into_owned());
+        };
 
-        // Spawn thread for stderr
-        thread::spawn(move || {
-            for line in stderr_reader.lines() {
-                if let Ok(line) = line {
-                    tx.send(line).expect("Failed to send stderr line");
-                }
+        // once the step is asked to stop, it is killed when the grace period expires
+        let mut kill_deadline: Option<Instant> = None;
+        let status = loop {
+            tokio::select! {
+                Some(line) = stdout.next_line(), if !stdout.done => handle_line(OutputStream::Stdout, line),
+                Some(line) = stderr.next_line(), if !stderr.done => handle_line(OutputStream::Stderr, line),
+                status = child.wait() => break status.expect("Failed to wait for child process"),
+                _ = interrupt.triggered(), if kill_deadline.is_none() => {
+                    terminate(&mut child, pid);
+                    kill_deadline = Some(Instant::now() + grace_period);
+                },
+                _ = kill_after(kill_deadline, interrupt) => {
+                    kill(&mut child, pid);
+                    child.wait().await.expect("Failed to wait for child process");
+                    return Err(CommandExecutionError::Interrupted);
+                },
             }
-        });
+        };
+
+        // like a shell, leave running what the step started in the background
+        #[cfg(unix)]
+        group.disarm();
 
-        // Process lines from both stdout and stderr
-        loop {
-            if let Ok(line) = rx.recv() {
-                output_handler(&line);
+        // read what the step wrote before exiting, until the pipes are closed unless
+        // background processes it started keep them open
+        let drain_timeout = tokio::time::sleep(OUTPUT_DRAIN_TIMEOUT);
+        tokio::pin!(drain_timeout);
+        while !(stdout.done && stderr.done) {
+            tokio::select! {
+                line = stdout.next_line(), if !stdout.done => line.into_iter().for_each(|line| handle_line(OutputStream::Stdout, line)),
+                line = stderr.next_line(), if !stderr.done => line.into_iter().for_each(|line| handle_line(OutputStream::Stderr, line)),
+                _ = &mut drain_timeout => break,
             }
+        }
+
+        if kill_deadline.is_some() {
+            // background processes started by the step may have survived it
+            kill(&mut child, pid);
+            return Err(CommandExecutionError::Interrupted);
+        }
+        if !status.success() {
+            return Err(CommandExecutionError::StepFailed(StepFailure {
+                step,
+                command: cmd.to_string(),
+                status,
+                output_tail: output_tail.into(),
+            }));
+        }
+        Ok(())
+    }
+}
+
+/// Splits an output pipe, or pseudo-terminal, into lines
+///
+/// Partial lines are kept when a read is cancelled and the last line is
+/// returned even if it doesn't end with a newline.
+struct OutputLines<R> {
+    /// `None` if the output is not captured
+    reader: Option<BufReader<R>>,
+    buf: Vec<u8>,
+    /// The pipe was closed
+    done: bool,
+    /// Only the last redraw of a line is kept, as a terminal would show it
+    terminal: bool,
+}
+
+impl<R: AsyncRead + Unpin> OutputLines<R> {
+    fn new(reader: Option<R>, terminal: bool) -> Self {
+        Self {
+            done: reader.is_none(),
+            reader: reader.map(BufReader::new),
+            buf: Vec::new(),
+            terminal,
+        }
+    }
 
-            if let Some(status) = child.try_wait().expect("Failed to query child process status") {
-                if !status.success() {
-                    panic!("Command '{}' failed with exit code: {}", cmd, status);
+    /// Returns the next line without its line ending, `None` once the pipe is closed
+    async fn next_line(&mut self) -> Option<Vec<u8>> {
+        let Some(reader) = &mut self.reader else {
+            self.done = true;
+            return None;
+        };
+        // cancel safe, the bytes read so far stay in `buf`
+        match reader.read_until(b'\n', &mut self.buf).await {
+            Ok(0) if self.buf.is_empty() => {
+                self.done = true;
+                return None;
+            },
+            Ok(_) => {},
+            Err(e) => {
+                log::warn!("Failed to read step output: {e}");
+                self.done = true;
+                if self.buf.is_empty() {
+                    return None;
                 }
-                break Ok(()); // Exit the loop if the child process has finished
+            },
+        }
+
+        let mut line = std::mem::take(&mut self.buf);
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+        // terminals end lines with \r\n, and steps writing \r\n themselves get \r\r\n
+        while line.ends_with(b"\r") {
+            line.pop();
+        }
+        // progress bars redraw the line after a \r, keep what they drew last
+        if let Some(redraw) = self.terminal.then(|| line.iter().rposition(|&b| b == b'\r')).flatten() {
+            line.drain(..=redraw);
+        }
+        Some(line)
+    }
+}
+
+/// Returns `true` if birb runs in the foreground of a terminal, interactive steps then take it while they run
+#[cfg(unix)]
+fn owns_terminal() -> bool {
+    // SAFETY: these functions have no memory safety requirements
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 && libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp() }
+}
+
+/// The terminal given to an interactive step, taken back when dropped
+///
+/// Like a shell does for its foreground job, the process group of the step
+/// becomes the foreground group of the terminal, so that the step can read
+/// from it and Ctrl-C goes to the step instead of birb.
+#[cfg(unix)]
+struct Foreground;
+
+#[cfg(unix)]
+impl Foreground {
+    fn give(pgid: libc::pid_t) -> Self {
+        set_foreground(pgid);
+        Self
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Foreground {
+    fn drop(&mut self) {
+        // SAFETY: getpgrp has no memory safety requirements
+        set_foreground(unsafe { libc::getpgrp() });
+    }
+}
+
+/// Kills the process group of a step when dropped before the step completed
+#[cfg(unix)]
+struct ProcessGroupGuard(Option<libc::pid_t>);
+
+#[cfg(unix)]
+impl ProcessGroupGuard {
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        if let Some(pgid) = self.0 {
+            // SAFETY: killpg has no memory safety requirements
+            unsafe { libc::killpg(pgid, libc::SIGKILL) };
+        }
+    }
+}
+
+/// Makes `pgid` the foreground process group of the terminal
+///
+/// `SIGTTOU` is blocked meanwhile, a background process doing this would be
+/// stopped otherwise. Only calls async-signal-safe functions.
+#[cfg(unix)]
+fn set_foreground(pgid: libc::pid_t) {
+    // SAFETY: the signal sets are initialized by sigemptyset and pthread_sigmask
+    unsafe {
+        let mut ttou = std::mem::zeroed::<libc::sigset_t>();
+        let mut previous = std::mem::zeroed::<libc::sigset_t>();
+        libc::sigemptyset(&mut ttou);
+        libc::sigaddset(&mut ttou, libc::SIGTTOU);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &ttou, &mut previous);
+        libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &previous, std::ptr::null_mut());
+    }
+}
+
+/// Completes when an interrupted step must be killed: when the interrupt is
+/// forced or the deadline expires, never if the step was not interrupted
+async fn kill_after(deadline: Option<Instant>, interrupt: &Interrupt) {
+    match deadline {
+        Some(deadline) => tokio::select! {
+            _ = interrupt.forced() => {},
+            _ = tokio::time::sleep_until(deadline) => {},
+        },
+        None => std::future::pending().await,
+    }
+}
+
+/// Asks the step, and every process it started, to stop
+fn terminate(child: &mut Child, pid: u32) {
+    #[cfg(unix)]
+    {
+        let _ = child;
+        // the step is the leader of its own process group
+        // SAFETY: killpg has no memory safety requirements
+        unsafe { libc::killpg(pid as libc::pid_t, libc::SIGINT) };
+    }
+    #[cfg(not(unix))]
+    kill(child, pid);
+}
+
+/// Kills the step and every process it started
+fn kill(child: &mut Child, pid: u32) {
+    #[cfg(unix)]
+    {
+        let _ = child;
+        // SAFETY: killpg has no memory safety requirements
+        unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        if let Err(e) = child.start_kill() {
+            log::warn!("Failed to kill child process: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn executor<F: FnMut(OutputLine) + Send>(output_handler: F) -> NaiveExecutor<F> {
+        NaiveExecutor {
+            output_handler,
+            grace_period: DEFAULT_GRACE_PERIOD,
+            interactive: false,
+            tty: false,
+        }
+    }
+
+    fn shell(commands: &[&str]) -> Vec<Command> {
+        commands.iter().map(|cmd| Command::Shell(cmd.to_string())).collect()
+    }
+
+    /// The steps don't wait for anything once their output is closed
+    #[tokio::test]
+    async fn steps_complete_without_delay() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut lines = Vec::new();
+        let mut executor = executor(|line: OutputLine| lines.push(line.text().into_owned()));
+
+        let started = std::time::Instant::now();
+        executor
+            .execute(dir.path(), &BTreeMap::new(), &shell(&["echo a", "echo b >&2", "printf c"]), &Interrupt::new())
+            .await
+            .unwrap();
+        assert!(started.elapsed() < OUTPUT_DRAIN_TIMEOUT * 3, "took {:?}", started.elapsed());
+        drop(executor);
+        assert_eq!(lines, ["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn interrupted_step_stops() {
+        let dir = tempfile::tempdir().unwrap();
+        let (env, steps) = (BTreeMap::new(), shell(&["sleep 30"]));
+        let interrupt = Interrupt::new();
+        let trigger = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            interrupt.trigger();
+            std::future::pending::<()>().await;
+        };
+
+        let started = std::time::Instant::now();
+        let mut executor = executor(|_| {});
+        let r = tokio::select! {
+            r = executor.execute(dir.path(), &env, &steps, &interrupt) => r,
+            _ = trigger => unreachable!(),
+        };
+        assert!(matches!(r, Err(CommandExecutionError::Interrupted)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    /// A cancelled step is killed, with what it started
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn dropped_step_is_killed() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("background.pid");
+        let (env, steps, interrupt) = (BTreeMap::new(), shell(&["sleep 30 & echo $! > background.pid; wait"]), Interrupt::new());
+        let started = async {
+            while std::fs::read_to_string(&pid_file).map_or(true, |pid| pid.trim().is_empty()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
             }
+        };
+
+        let mut executor = executor(|_| {});
+        tokio::select! {
+            _ = executor.execute(dir.path(), &env, &steps, &interrupt) => panic!("the step completed"),
+            _ = started => {},
+        }
+
+        let pid: libc::pid_t = std::fs::read_to_string(&pid_file).unwrap().trim().parse().unwrap();
+        // SAFETY: kill has no memory safety requirements
+        let is_running = || unsafe { libc::kill(pid, 0) == 0 };
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while is_running() && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
         }
+        assert!(!is_running(), "the background process of the step is still running");
     }
 }
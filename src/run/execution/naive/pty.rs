@@ -0,0 +1,95 @@
+use std::{io, os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd}, pin::Pin, task::{ready, Context, Poll}};
+
+use tokio::io::{unix::AsyncFd, AsyncRead, ReadBuf};
+
+/// Size given to the pseudo-terminals when birb does not run in a terminal
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+/// A pseudo-terminal, the step writes to the slave side and birb reads the master side
+pub struct Pty {
+    pub master: PtyReader,
+    pub slave: OwnedFd,
+}
+
+impl Pty {
+    /// Opens a pseudo-terminal as large as the terminal of birb
+    pub fn open() -> io::Result<Self> {
+        let size = terminal_size();
+        let (mut master, mut slave) = (-1, -1);
+        // SAFETY: the pointers are valid for the duration of the call, the name is not written
+        if unsafe { libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null(), &size) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: openpty opened both file descriptors, nothing else owns them
+        let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+        // the steps only get the slave side, as their stdout or stderr
+        set_flags(master.as_raw_fd(), libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC)?;
+        set_flags(slave.as_raw_fd(), libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC)?;
+        Ok(Self {
+            master: PtyReader::new(master)?,
+            slave,
+        })
+    }
+}
+
+/// The master side of a pseudo-terminal, read asynchronously
+pub struct PtyReader(AsyncFd<OwnedFd>);
+
+impl PtyReader {
+    fn new(master: OwnedFd) -> io::Result<Self> {
+        set_flags(master.as_raw_fd(), libc::F_GETFL, libc::F_SETFL, libc::O_NONBLOCK)?;
+        Ok(Self(AsyncFd::new(master)?))
+    }
+}
+
+impl AsyncRead for PtyReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            let read = guard.try_io(|fd| {
+                // SAFETY: `unfilled` is valid for writes of its length
+                let n = unsafe { libc::read(fd.as_raw_fd(), unfilled.as_mut_ptr().cast(), unfilled.len()) };
+                if n < 0 { Err(io::Error::last_os_error()) } else { Ok(n as usize) }
+            });
+            match read {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                },
+                // Linux reports EIO instead of the end of file once every process closed the slave side
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::EIO) => return Poll::Ready(Ok(())),
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                // not readable anymore, wait again
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+/// The size of the terminal of birb, or [`DEFAULT_SIZE`]
+fn terminal_size() -> libc::winsize {
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: winsize is plain data
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        // SAFETY: TIOCGWINSZ writes a winsize
+        if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } == 0 && size.ws_col > 0 {
+            return size;
+        }
+    }
+    libc::winsize {
+        ws_col: DEFAULT_SIZE.0,
+        ws_row: DEFAULT_SIZE.1,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+fn set_flags(fd: RawFd, get: libc::c_int, set: libc::c_int, flags: libc::c_int) -> io::Result<()> {
+    // SAFETY: fcntl with these commands has no memory safety requirements
+    let current = unsafe { libc::fcntl(fd, get) };
+    if current < 0 || unsafe { libc::fcntl(fd, set, current | flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -1,10 +1,11 @@
-use std::{collections::{HashMap, HashSet}, fmt::Debug, hash::Hash, task::Poll};
+use std::{cmp::Reverse, collections::{BTreeMap, HashMap, HashSet}, fmt::Debug, hash::Hash, task::Poll};
 
 use linked_hash_map::LinkedHashMap;
 use linked_hash_set::LinkedHashSet;
 use tokio::task::JoinSet;
 
-// TODO add a number to describe how "heavy" a task is, so that we can better schedule them
+use crate::run::jobserver::Jobserver;
+
 #[derive(Debug)]
 struct TaskTreeQueue<T: Hash + Eq> {
     /// Sorted list of tasks with their dependencies
@@ -12,6 +13,10 @@ struct TaskTreeQueue<T: Hash + Eq> {
 
     /// For fast lookup of dependant tasks
     parents: HashMap<T, HashSet<T>>,
+
+    /// Weight of the longest path from each task to the root, ready tasks
+    /// with the highest priority are taken first
+    priorities: HashMap<T, u64>,
 }
 
 impl<T> TaskTreeQueue<T>
@@ -22,6 +27,7 @@ where
         Self {
             queue: LinkedHashMap::new(),
             parents: HashMap::new(),
+            priorities: HashMap::new(),
         }
     }
 
@@ -45,6 +51,38 @@ where
         // TODO maybe debug assert of consistency
     }
 
+    /// Computes the priority of every queued task from the weights, missing tasks weigh 0
+    ///
+    /// The priority of a task is the weight of the heaviest path from it to
+    /// the root, itself included. Must be called after all the tasks are added.
+    pub fn prioritize(&mut self, weights: &HashMap<T, u64>) {
+        fn priority<T: Hash + Eq + Clone>(
+            task: &T,
+            weights: &HashMap<T, u64>,
+            parents: &HashMap<T, HashSet<T>>,
+            priorities: &mut HashMap<T, u64>,
+        ) -> u64 {
+            if let Some(priority) = priorities.get(task) {
+                return *priority;
+            }
+            let heaviest_parent = parents
+                .get(task)
+                .into_iter()
+                .flatten()
+                .map(|parent| priority(parent, weights, parents, priorities))
+                .max()
+                .unwrap_or(0);
+            let p = weights.get(task).copied().unwrap_or(0) + heaviest_parent;
+            priorities.insert(task.clone(), p);
+            p
+        }
+
+        self.priorities.clear();
+        for task in self.queue.keys() {
+            priority(task, weights, &self.parents, &mut self.priorities);
+        }
+    }
+
     pub fn mark_fulfilled(&mut self, task: &T) {
         // TODO we should assert (or return a result)
         // that it's dependencies are fulfilled
@@ -62,40 +100,202 @@ where
         }
     }
 
+    /// Removes the tasks depending on a failed task from the queue
+    ///
+    /// Returns the tasks that can't run anymore because they transitively
+    /// depend on `task`, in queue order.
+    pub fn mark_failed(&mut self, task: &T) -> Vec<T> {
+        let deps = self.queue.remove(task);
+        assert!(deps.is_none(), "Task {:?} was not taken", task);
+
+        let mut dependents = HashSet::new();
+        let mut stack = vec![task.clone()];
+        while let Some(task) = stack.pop() {
+            for parent in self.parents.remove(&task).into_iter().flatten() {
+                if dependents.insert(parent.clone()) {
+                    stack.push(parent);
+                }
+            }
+        }
+
+        let skipped = self.queue
+            .keys()
+            .filter(|task| dependents.contains(task))
+            .cloned()
+            .collect::<Vec<_>>();
+        for task in &skipped {
+            self.queue.remove(task);
+        }
+        skipped
+    }
+
+    #[cfg(test)]
     pub fn take_next_ready_task(&mut self) -> Poll<Option<T>> {
+        self.take_next_ready_task_where(|_| true, |_| false)
+    }
+
+    /// Same as [`Self::take_next_ready_task`], but only takes a ready task accepted by `can_start`
+    ///
+    /// If the ready task with the highest priority is `exclusive` and can't
+    /// start yet, no task is taken, so that it isn't overtaken until the end.
+    pub fn take_next_ready_task_where(&mut self, can_start: impl Fn(&T) -> bool, exclusive: impl Fn(&T) -> bool) -> Poll<Option<T>> {
+        let next = self.next_ready_task_where(can_start, exclusive);
+        if let Poll::Ready(Some(next)) = &next {
+            let deps = self.queue.remove(next);
+            assert!(deps.unwrap().is_empty());
+        }
+        next
+    }
+
+    /// The task [`Self::take_next_ready_task_where`] would take, left in the queue
+    pub fn next_ready_task_where(&self, can_start: impl Fn(&T) -> bool, exclusive: impl Fn(&T) -> bool) -> Poll<Option<T>> {
         if self.queue.is_empty() {
             // no more tasks
             return Poll::Ready(None);
         }
 
-        let next = self.queue
+        // on equal priorities, the first in the queue
+        let priority = |(task, _): &(&T, &HashSet<T>)| Reverse(self.priorities.get(*task).copied().unwrap_or(0));
+        let ready = self.queue
             .iter()
-            .find(|(_, deps)| deps.is_empty())
+            .filter(|(_, deps)| deps.is_empty());
+
+        if let Some((first, _)) = ready.clone().min_by_key(priority)
+            && exclusive(first)
+            && !can_start(first)
+        {
+            // wait for the running tasks to finish
+            return Poll::Pending;
+        }
+
+        let next = ready
+            .filter(|(task, _)| can_start(task))
+            .min_by_key(priority)
             .map(|(task, _)| task.clone());
 
-        let Some(next) = next else {
+        match next {
+            Some(next) => Poll::Ready(Some(next)),
             // no task is ready yet
-            return Poll::Pending;
-        };
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Error returned in keep-going mode when at least one task failed
+#[derive(Debug, thiserror::Error)]
+#[error("{} tasks failed", failures.len())]
+pub struct TasksFailed<Ref: Debug> {
+    /// Failed tasks with their error, in completion order
+    pub failures: Vec<(Ref, anyhow::Error)>,
+    /// Tasks that were not run because they depend on a failed task
+    pub skipped: Vec<Ref>,
+}
+
+/// Amounts of named resources, e.g. `cpu`, `mem` or a lock
+pub type Resources = BTreeMap<String, u64>;
 
-        let deps = self.queue.remove(&next);
-        assert!(deps.unwrap().is_empty());
-        Poll::Ready(Some(next))
+/// Resources in use by the running tasks
+#[derive(Debug)]
+struct ResourcePool {
+    limits: HashMap<String, u64>,
+    used: HashMap<String, u64>,
+}
+
+impl ResourcePool {
+    fn new(limits: HashMap<String, u64>) -> Self {
+        Self {
+            limits,
+            used: HashMap::new(),
+        }
     }
+
+    /// The amounts counted against the limits, resources without a limit are
+    /// not counted and a need above its limit is capped so the task can run alone
+    fn counted<'a>(&'a self, needs: Option<&'a Resources>) -> impl Iterator<Item = (&'a String, u64, u64)> {
+        needs
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, amount)| self.limits.get(name).map(|limit| (name, (*amount).min(*limit), *limit)))
+    }
+
+    fn is_available(&self, needs: Option<&Resources>) -> bool {
+        self.counted(needs)
+            .all(|(name, amount, limit)| self.used.get(name).copied().unwrap_or(0) + amount <= limit)
+    }
+
+    fn acquire(&mut self, needs: Option<&Resources>) {
+        let counted = self.counted(needs).map(|(name, amount, _)| (name.clone(), amount)).collect::<Vec<_>>();
+        for (name, amount) in counted {
+            *self.used.entry(name).or_default() += amount;
+        }
+    }
+
+    fn release(&mut self, needs: Option<&Resources>) {
+        let counted = self.counted(needs).map(|(name, amount, _)| (name.clone(), amount)).collect::<Vec<_>>();
+        for (name, amount) in counted {
+            *self.used.entry(name).or_default() -= amount;
+        }
+    }
+}
+
+/// How [`execute_tasks_concurrently`] schedules the tasks
+#[derive(Debug, Clone)]
+pub struct SchedulerConfig<Ref> {
+    pub max_concurrency: usize,
+    /// Keep running the tasks that don't depend on a failed task
+    pub keep_going: bool,
+    /// Expected cost of the tasks (e.g. their duration in milliseconds), the
+    /// tasks on the heaviest paths are started first. Missing tasks weigh 0.
+    pub weights: HashMap<Ref, u64>,
+    /// Resources needed by the tasks, a task only starts when all of them are available
+    pub resources: HashMap<Ref, Resources>,
+    /// Available amount of each resource, resources without a limit are unlimited
+    pub limits: HashMap<String, u64>,
+    /// Tasks that run alone, e.g. interactive tasks: they wait for the running
+    /// tasks to finish and no other task starts while they run. Once one of them
+    /// is the ready task with the highest priority, no other task starts before it.
+    pub exclusive: HashSet<Ref>,
+    /// Every running task holds a token of the jobserver, taken before the task
+    /// is picked so the tokens don't change the order in which the tasks start
+    pub jobserver: Option<Jobserver>,
 }
 
+impl<Ref> SchedulerConfig<Ref> {
+    pub fn new(max_concurrency: usize) -> Self {
+        Self {
+            max_concurrency,
+            keep_going: false,
+            weights: HashMap::new(),
+            resources: HashMap::new(),
+            limits: HashMap::new(),
+            exclusive: HashSet::new(),
+            jobserver: None,
+        }
+    }
+}
+
+/// Runs the tasks of the queue once their dependencies are fulfilled
+///
+/// By default the scheduling stops at the first failure. With `keep_going`,
+/// every task not depending on a failed one is still run and all the
+/// failures are returned together as a [`TasksFailed`] error.
 pub async fn execute_tasks_concurrently<Ref, F>(
-    max_concurrency: usize,
+    config: SchedulerConfig<Ref>,
     queue: impl IntoIterator<Item = Ref>,
     deps_graph: LinkedHashMap<Ref, LinkedHashSet<Ref>>,
     run_while: impl Fn() -> bool + Send + Sync + 'static, // TODO test
     run: impl Fn(Ref) -> F,
 ) -> anyhow::Result<()>
 where
-    Ref: Debug + Hash + Eq + Clone + Send + 'static,
+    Ref: Debug + Hash + Eq + Clone + Send + Sync + 'static,
     F: std::future::Future<Output = Result<(), anyhow::Error>> + Send + 'static,
 {
     // TODO check max_concurrency > 0
+    let SchedulerConfig { max_concurrency, keep_going, weights, resources, limits, exclusive, jobserver } = config;
+    let mut pool = ResourcePool::new(limits);
+
+    // the tokens requested for tasks that won't start are given back
+    let _unused_tokens = UnusedTokens(jobserver.clone());
 
     let mut running = JoinSet::<Result<Ref, (Ref, anyhow::Error)>>::new();
 
@@ -105,25 +305,64 @@ where
         let deps = deps_graph.get(&task).cloned().unwrap_or_default();
         tq.add(task, deps);
     }
+    tq.prioritize(&weights);
 
     let mut interrupted = false;
+    let mut waiting_for_token = false;
+    // an exclusive task is running
+    let mut alone = false;
+
+    // only used in keep-going mode
+    let mut failures = Vec::new();
+    let mut skipped = Vec::new();
 
     loop {
         // feed the running tasks
         while running.len() < max_concurrency {
+            let mut token = None;
             let next = if run_while() {
-                tq.take_next_ready_task()
+                let can_start = |task: &Ref| {
+                    let can_run_beside = if exclusive.contains(task) { running.is_empty() } else { !alone };
+                    can_run_beside && pool.is_available(resources.get(task))
+                };
+                let exclusive = |task: &Ref| exclusive.contains(task);
+                match tq.next_ready_task_where(can_start, exclusive) {
+                    // the next task only starts with a token
+                    Poll::Ready(Some(_)) => match jobserver.as_ref().map(Jobserver::try_acquire) {
+                        Some(Ok(None)) => {
+                            waiting_for_token = true;
+                            Poll::Pending
+                        },
+                        acquired => {
+                            token = match acquired {
+                                Some(Ok(token)) => token,
+                                Some(Err(e)) => {
+                                    // the task runs anyway
+                                    log::warn!("Failed to acquire a jobserver token: {e}");
+                                    None
+                                },
+                                None => None,
+                            };
+                            tq.take_next_ready_task_where(can_start, exclusive)
+                        },
+                    },
+                    next => next,
+                }
             } else {
                 // stop feeding new tasks
                 interrupted = true;
                 Poll::Ready(None)
             };
             match next {
-                Poll::Pending => break, // no more ready tasks
+                Poll::Pending => break, // no more ready tasks, not enough resources or no token
                 Poll::Ready(Some(next)) => {
+                    pool.acquire(resources.get(&next));
+                    alone = exclusive.contains(&next);
                     let f = run(next.clone());
                     running.spawn({
                         async move {
+                            // released when the task is done
+                            let _token = token;
                             // TODO avoid clone with a match
                             f.await.map(|_| next.clone()).map_err(|e| (next, e))
                         }
@@ -131,48 +370,98 @@ where
                 },
                 Poll::Ready(None) => {
                     // no more task to run, wait for the running ones to finish
-                    let all_failures = running
+                    failures.extend(running
                         .join_all().await
                         .into_iter()
-                        .filter_map(|r| r.err())
-                        .collect::<Vec<_>>();
-                    if all_failures.is_empty() {
+                        .filter_map(|r| r.err()));
+                    if failures.is_empty() {
                         if !interrupted {
                             return Ok(());
                         } else {
                             anyhow::bail!("Execution interrupted");
                         }
+                    } else if keep_going {
+                        return Err(TasksFailed { failures, skipped }.into());
                     } else {
-                        anyhow::bail!("One of the tasks failed: {:?}", all_failures);
+                        return Err(first_failure(failures));
                     }
                 }
             }
         }
 
-        // pool is full or no more ready tasks, wait for one to finish
-        let Some(r) = running.join_next().await else {
+        // pool is full, no more ready tasks or no token, wait for a task to finish or a token
+        let r = tokio::select! {
+            r = running.join_next(), if !running.is_empty() => r,
+            _ = tokens_changed(jobserver.as_ref()), if waiting_for_token => {
+                // the interrupts are checked again too
+                waiting_for_token = false;
+                continue;
+            },
+            else => None,
+        };
+        waiting_for_token = false;
+        let Some(r) = r else {
             anyhow::bail!("No more running tasks, but queue is waiting");
         };
 
         // TODO handle join error
         let r = r.unwrap();
+        let (Ok(task) | Err((task, _))) = &r;
+        pool.release(resources.get(task));
+        alone &= !exclusive.contains(task);
 
         match r {
             Ok(task) => tq.mark_fulfilled(&task),
+            Err((task, e)) if keep_going => {
+                skipped.extend(tq.mark_failed(&task));
+                failures.push((task, e));
+            },
             Err(e) => {
-                running.abort_all();
-                let mut all_failures = running
-                    .join_all().await
-                    .into_iter()
-                    .filter_map(|r| r.err())
-                    .collect::<Vec<_>>();
-                all_failures.insert(0, e);
-                anyhow::bail!("One of the tasks failed: {:?}", all_failures);
+                // Let the running tasks finish, aborting them would leave
+                // their processes running in the background.
+                let mut all_failures = vec![e];
+                while let Some(r) = running.join_next().await {
+                    match r {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => all_failures.push(e),
+                        Err(e) => log::error!("Failed to join task: {e}"),
+                    }
+                }
+                return Err(first_failure(all_failures));
             },
         }
     }
 }
 
+/// Waits until [`Jobserver::changed`] returns, forever without a jobserver
+async fn tokens_changed(jobserver: Option<&Jobserver>) {
+    match jobserver {
+        Some(jobserver) => jobserver.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Releases the unused tokens of the jobserver when the scheduling ends
+struct UnusedTokens(Option<Jobserver>);
+
+impl Drop for UnusedTokens {
+    fn drop(&mut self) {
+        if let Some(jobserver) = &self.0 {
+            jobserver.release_unused();
+        }
+    }
+}
+
+/// Returns the error of the first failed task, the others are only logged
+fn first_failure<Ref: Debug>(failures: Vec<(Ref, anyhow::Error)>) -> anyhow::Error {
+    let mut failures = failures.into_iter();
+    let (_, first) = failures.next().expect("at least one failure");
+    for (task, e) in failures {
+        log::warn!("Task {task:?} also failed: {e}");
+    }
+    first
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::{Arc, Mutex};
@@ -244,10 +533,46 @@ mod tests {
         tq.mark_fulfilled(&3); // <- This should panic
     }
 
+    /// Test that the transitive dependents of a failed task are skipped
+    #[test]
+    fn task_queue_mark_failed() {
+        let mut tq = TaskTreeQueue::new();
+        tq.add(1, [2, 3]);
+        tq.add(2, [4]);
+        tq.add(3, []);
+        tq.add(4, []);
+        tq.add(5, []);
+
+        assert_eq!(tq.take_next_ready_task(), Poll::Ready(Some(3)));
+        assert_eq!(tq.take_next_ready_task(), Poll::Ready(Some(4)));
+        assert_eq!(tq.take_next_ready_task(), Poll::Ready(Some(5)));
+
+        assert_eq!(tq.mark_failed(&4), vec![1, 2]);
+        tq.mark_fulfilled(&3);
+        tq.mark_fulfilled(&5);
+        assert_eq!(tq.take_next_ready_task(), Poll::Ready(None));
+    }
+
+    /// Test that the ready task on the heaviest path is taken first
+    #[test]
+    fn task_queue_priorities() {
+        let mut tq = TaskTreeQueue::new();
+        tq.add(1, [2, 3]);
+        tq.add(2, [4]);
+        tq.add(3, []);
+        tq.add(4, []);
+        tq.add(5, []);
+        tq.prioritize(&[(3, 10), (4, 20), (5, 25)].into_iter().collect());
+
+        assert_eq!(tq.take_next_ready_task(), Poll::Ready(Some(5)));
+        assert_eq!(tq.take_next_ready_task(), Poll::Ready(Some(4)));
+        assert_eq!(tq.take_next_ready_task(), Poll::Ready(Some(3)));
+    }
+
     #[tokio::test]
     async fn null_run() {
         execute_tasks_concurrently(
-            1,
+            SchedulerConfig::new(1),
             vec![],
             Default::default(),
             || true,
@@ -260,7 +585,7 @@ mod tests {
         let results = Arc::new(Mutex::new(vec![]));
 
         execute_tasks_concurrently(
-            1,
+            SchedulerConfig::new(1),
             vec![1, 2, 3],
             Default::default(),
             || true,
@@ -281,7 +606,7 @@ mod tests {
         let results = Arc::new(Mutex::new(vec![]));
 
         execute_tasks_concurrently(
-            1,
+            SchedulerConfig::new(1),
             vec![1, 2, 3],
             [(1, [2].into_iter().collect())].into_iter().collect(), // 1 depends on 2
             || true,
@@ -327,7 +652,7 @@ mod tests {
 
         let results2 = results.clone();
         let j0 = execute_tasks_concurrently(
-            1000,
+            SchedulerConfig::new(1000),
             vec![1, 2, 3],
             [
                 (3, [1].into_iter().collect()), // 3 depends on 1
@@ -378,6 +703,179 @@ mod tests {
         assert_eq!(*results.lock().unwrap(), vec![1, 2, 3]);
     }
 
+    /// Tasks holding the same lock never run at the same time, other tasks run beside them
+    #[tokio::test]
+    async fn resources() {
+        let running = Arc::new(Mutex::new(HashSet::new()));
+        let overlaps = Arc::new(Mutex::new(vec![]));
+
+        let lock = Resources::from([("lock".to_string(), 1)]);
+        execute_tasks_concurrently(
+            SchedulerConfig {
+                resources: [(1, lock.clone()), (2, lock.clone())].into_iter().collect(),
+                limits: [("lock".to_string(), 1)].into_iter().collect(),
+                ..SchedulerConfig::new(3)
+            },
+            vec![1, 2, 3],
+            LinkedHashMap::new(),
+            || true,
+            |t| {
+                let running = running.clone();
+                let overlaps = overlaps.clone();
+                async move {
+                    {
+                        let mut running = running.lock().unwrap();
+                        overlaps.lock().unwrap().extend(running.iter().map(|other| (*other, t)));
+                        running.insert(t);
+                    }
+                    // 3 runs beside both 1 and 2
+                    tokio::time::sleep(std::time::Duration::from_millis(if t == 3 { 150 } else { 50 })).await;
+                    running.lock().unwrap().remove(&t);
+                    Ok(())
+                }
+            },
+        ).await.unwrap();
+
+        let mut overlaps = overlaps.lock().unwrap().clone();
+        overlaps.sort();
+        assert_eq!(overlaps, vec![(1, 3), (3, 2)]);
+    }
+
+    /// An exclusive task runs alone, and once it has the highest priority it is not overtaken by the other ready tasks
+    #[tokio::test]
+    async fn exclusive() {
+        let running = Arc::new(Mutex::new(HashSet::new()));
+        let started = Arc::new(Mutex::new(vec![]));
+        let overlaps = Arc::new(Mutex::new(vec![]));
+
+        execute_tasks_concurrently(
+            SchedulerConfig {
+                weights: [(1, 3), (2, 4), (3, 1), (4, 1)].into_iter().collect(),
+                exclusive: [1].into_iter().collect(),
+                ..SchedulerConfig::new(2)
+            },
+            vec![1, 2, 3, 4],
+            LinkedHashMap::new(),
+            || true,
+            |t| {
+                let running = running.clone();
+                let started = started.clone();
+                let overlaps = overlaps.clone();
+                async move {
+                    started.lock().unwrap().push(t);
+                    {
+                        let mut running = running.lock().unwrap();
+                        overlaps.lock().unwrap().extend(running.iter().map(|other| (*other, t)));
+                        running.insert(t);
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+                    running.lock().unwrap().remove(&t);
+                    Ok(())
+                }
+            },
+        ).await.unwrap();
+
+        assert_eq!(*started.lock().unwrap(), vec![2, 1, 3, 4]);
+        assert_eq!(*overlaps.lock().unwrap(), vec![(3, 4)]);
+    }
+
+    /// With a jobserver, no more tasks than tokens run and they still start by priority
+    #[tokio::test]
+    async fn jobserver() {
+        let running = Arc::new(Mutex::new(0));
+        let max_running = Arc::new(Mutex::new(0));
+        let started = Arc::new(Mutex::new(vec![]));
+
+        execute_tasks_concurrently(
+            SchedulerConfig {
+                weights: (1..=5).map(|t| (t, t as u64)).collect(),
+                jobserver: Some(Jobserver::new(2).unwrap()),
+                ..SchedulerConfig::new(10)
+            },
+            vec![1, 2, 3, 4, 5],
+            LinkedHashMap::new(),
+            || true,
+            |t| {
+                let running = running.clone();
+                let max_running = max_running.clone();
+                let started = started.clone();
+                async move {
+                    started.lock().unwrap().push(t);
+                    {
+                        let mut running = running.lock().unwrap();
+                        *running += 1;
+                        let mut max_running = max_running.lock().unwrap();
+                        *max_running = (*max_running).max(*running);
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+                    *running.lock().unwrap() -= 1;
+                    Ok(())
+                }
+            },
+        ).await.unwrap();
+
+        assert_eq!(*max_running.lock().unwrap(), 2);
+        assert_eq!(*started.lock().unwrap(), vec![5, 4, 3, 2, 1]);
+    }
+
+    /// A failure stops the scheduling, the running tasks are completed and the error is returned
+    #[tokio::test]
+    async fn failure() {
+        let results = Arc::new(Mutex::new(vec![]));
+
+        let r = execute_tasks_concurrently(
+            SchedulerConfig::new(2),
+            vec![1, 2, 3],
+            [(3, [1, 2].into_iter().collect())].into_iter().collect(), // 3 depends on 1 and 2
+            || true,
+            |t| {
+                let results = results.clone();
+                async move {
+                    if t == 1 {
+                        anyhow::bail!("task 1 failed");
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    results.lock().unwrap().push(t);
+                    Ok(())
+                }
+            },
+        ).await;
+
+        assert_eq!(r.unwrap_err().to_string(), "task 1 failed");
+        assert_eq!(*results.lock().unwrap(), vec![2]);
+    }
+
+    /// In keep-going mode the tasks not depending on the failed one still run
+    #[tokio::test]
+    async fn keep_going() {
+        let results = Arc::new(Mutex::new(vec![]));
+
+        let r = execute_tasks_concurrently(
+            SchedulerConfig { keep_going: true, ..SchedulerConfig::new(1) },
+            vec![1, 2, 3, 4],
+            [
+                (2, [1].into_iter().collect()), // 2 depends on 1
+                (3, [2].into_iter().collect()), // 3 depends on 2
+            ].into_iter().collect(),
+            || true,
+            |t| {
+                let results = results.clone();
+                async move {
+                    if t == 1 || t == 4 {
+                        anyhow::bail!("task {t} failed");
+                    }
+                    results.lock().unwrap().push(t);
+                    Ok(())
+                }
+            },
+        ).await;
+
+        let e = r.unwrap_err().downcast::<TasksFailed<i32>>().unwrap();
+        assert_eq!(e.failures.iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!(e.skipped, vec![2, 3]);
+        assert!(results.lock().unwrap().is_empty());
+    }
+
     /// Same as [`less_trivial_run_2`] but with max_concurrency = 1 should deadlock
     #[tokio::test]
     #[should_panic]
@@ -410,7 +908,7 @@ mod tests {
 
         let results2 = results.clone();
         let j0 = execute_tasks_concurrently(
-            1,
+            SchedulerConfig::new(1),
             vec![1, 2, 3],
             [
                 (3, [1].into_iter().collect()), // 3 depends on 1
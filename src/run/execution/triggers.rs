@@ -1,18 +1,115 @@
 use std::{
-    collections::HashMap, error::Error, fs::File, io::{BufReader, Read}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::SystemTime
+    collections::HashMap, error::Error, fmt::Display, fs::File, io::{BufReader, Read}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::SystemTime
 };
 
 use anyhow::anyhow;
+use pathdiff::diff_paths;
 use sha2::{Digest, Sha256};
 
-use crate::task::InstantiatedTask;
+use crate::task::{InstantiatedTask, ResolvedTaskInvocation};
+
+pub mod persistent;
+
+/// The outcome of a trigger check, with the reason that led to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TriggerDecision {
+    Run(RunReason),
+    Skip(SkipReason),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunReason {
+    /// The task has steps but no outputs, so it always runs
+    NoOutputs,
+    /// The task is phony
+    Phony,
+    /// An output does not exist
+    OutputMissing(PathBuf),
+    /// A source is newer than an output
+    SourceNewer { source: PathBuf, output: PathBuf },
+    /// A source is an output of a task that will run first
+    SourceRegenerated(PathBuf),
+    /// The content of a source changed since the last run
+    SourceChanged(PathBuf),
+    /// An output was modified since the last run
+    OutputChanged(PathBuf),
+    /// Part of the rendered task changed since the last run
+    RecipeChanged(RecipePart),
+}
+
+/// The parts of an instantiated task, other than its inputs, that determine what it does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecipePart {
+    Steps,
+    Env,
+    Workdir,
+    Args,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The task has no steps, so there is nothing to run
+    NoSteps,
+    /// All the outputs are newer than the sources
+    UpToDate,
+}
+
+impl TriggerDecision {
+    pub fn should_run(&self) -> bool {
+        matches!(self, TriggerDecision::Run(_))
+    }
+
+    /// Displays the decision with paths relative to the given directory
+    pub fn display_relative<'a>(&'a self, from: &'a Path) -> impl Display {
+        TriggerDecisionDisplayRelative(self, from)
+    }
+}
+
+impl Display for TriggerDecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.display_relative("".as_ref()).fmt(f)
+    }
+}
+
+struct TriggerDecisionDisplayRelative<'a>(&'a TriggerDecision, &'a Path);
+
+impl Display for TriggerDecisionDisplayRelative<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rel = |path: &Path| diff_paths(path, self.1).unwrap_or_else(|| path.to_path_buf()).display().to_string();
+        match self.0 {
+            TriggerDecision::Run(RunReason::NoOutputs) => write!(f, "no outputs, always runs"),
+            TriggerDecision::Run(RunReason::Phony) => write!(f, "phony"),
+            TriggerDecision::Run(RunReason::OutputMissing(output)) => write!(f, "output {} missing", rel(output)),
+            TriggerDecision::Run(RunReason::SourceNewer { source, output }) => {
+                write!(f, "source {} newer than output {}", rel(source), rel(output))
+            }
+            TriggerDecision::Run(RunReason::SourceRegenerated(source)) => write!(f, "source {} will be regenerated", rel(source)),
+            TriggerDecision::Run(RunReason::SourceChanged(source)) => write!(f, "source {} changed", rel(source)),
+            TriggerDecision::Run(RunReason::OutputChanged(output)) => write!(f, "output {} was modified", rel(output)),
+            TriggerDecision::Run(RunReason::RecipeChanged(part)) => write!(f, "{part} changed"),
+            TriggerDecision::Skip(SkipReason::NoSteps) => write!(f, "no steps, never runs"),
+            TriggerDecision::Skip(SkipReason::UpToDate) => write!(f, "up-to-date"),
+        }
+    }
+}
+
+impl Display for RecipePart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecipePart::Steps => write!(f, "steps"),
+            RecipePart::Env => write!(f, "env"),
+            RecipePart::Workdir => write!(f, "workdir"),
+            RecipePart::Args => write!(f, "arguments"),
+        }
+    }
+}
 
 pub trait TaskTriggerChecker {
     type TaskContext;
     type RunError: Error + Send + Sync + 'static;
     type OutputCheckError: Error + Send + Sync + 'static;
-    fn new_task_context(&mut self) -> Self::TaskContext;
-    fn should_run(&mut self, task: &InstantiatedTask, context: &mut Self::TaskContext) -> Result<bool, Self::RunError>;
+    fn new_task_context(&mut self, invocation: &ResolvedTaskInvocation) -> Self::TaskContext;
+    fn should_run(&mut self, task: &InstantiatedTask, context: &mut Self::TaskContext) -> Result<TriggerDecision, Self::RunError>;
     fn check_outputs(&mut self, task: &InstantiatedTask, context: &mut Self::TaskContext, executed: bool) -> Result<(), Self::OutputCheckError>;
 }
 
@@ -20,10 +117,10 @@ impl<T: TaskTriggerChecker> TaskTriggerChecker for Arc<Mutex<T>> {
     type TaskContext = T::TaskContext;
     type RunError = T::RunError;
     type OutputCheckError = T::OutputCheckError;
-    fn new_task_context(&mut self) -> Self::TaskContext {
-        self.lock().unwrap().new_task_context()
+    fn new_task_context(&mut self, invocation: &ResolvedTaskInvocation) -> Self::TaskContext {
+        self.lock().unwrap().new_task_context(invocation)
     }
-    fn should_run(&mut self, task: &InstantiatedTask, context: &mut Self::TaskContext) -> Result<bool, Self::RunError> {
+    fn should_run(&mut self, task: &InstantiatedTask, context: &mut Self::TaskContext) -> Result<TriggerDecision, Self::RunError> {
         self.lock().unwrap().should_run(task, context)
     }
     fn check_outputs(&mut self, task: &InstantiatedTask, context: &mut Self::TaskContext, executed: bool) -> Result<(), Self::OutputCheckError> {
@@ -31,19 +128,30 @@ impl<T: TaskTriggerChecker> TaskTriggerChecker for Arc<Mutex<T>> {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct NaiveTriggerChecker {
     not_changed: HashMap<PathBuf, bool>,
 }
 
+impl NaiveTriggerChecker {
+    /// Takes in what a clone of this checker learnt about the outputs
+    ///
+    /// An output that changed stays changed, whichever checker saw it.
+    pub fn merge(&mut self, other: NaiveTriggerChecker) {
+        for (path, not_changed) in other.not_changed {
+            *self.not_changed.entry(path).or_insert(not_changed) &= not_changed;
+        }
+    }
+}
+
 impl TaskTriggerChecker for NaiveTriggerChecker {
     type TaskContext = HashMap<PathBuf, Hash>;
     type RunError = RunError;
     type OutputCheckError = OutputCheckError;
-    fn new_task_context(&mut self) -> Self::TaskContext {
+    fn new_task_context(&mut self, _invocation: &ResolvedTaskInvocation) -> Self::TaskContext {
         Default::default()
     }
-    fn should_run(&mut self, task: &InstantiatedTask, context: &mut Self::TaskContext) -> Result<bool, Self::RunError> {
+    fn should_run(&mut self, task: &InstantiatedTask, context: &mut Self::TaskContext) -> Result<TriggerDecision, Self::RunError> {
         let output_hashes = context;
 
         let has_no_outputs = task.resolve_outputs().next().is_none();
@@ -53,19 +161,19 @@ impl TaskTriggerChecker for NaiveTriggerChecker {
         // we assume it should always run.
         if has_no_outputs && !has_no_command {
             log::trace!("Task {:?} has no outputs, will always run", task.name);
-            return Ok(true);
+            return Ok(TriggerDecision::Run(RunReason::NoOutputs));
         }
 
         // If a command has no steps, it cannot be run.
         if has_no_command {
             log::trace!("Task {:?} has no commands, will never run", task.name);
-            return Ok(false);
+            return Ok(TriggerDecision::Skip(SkipReason::NoSteps));
         }
 
         log::trace!("Checking sources changes for task {:?}", task.name);
-        let changed = sources_changed(task, output_hashes, &self.not_changed)?;
-        log::trace!("Task {:?} changed: {}", task.name, changed);
-        Ok(changed)
+        let decision = sources_changed(task, output_hashes, &self.not_changed)?;
+        log::trace!("Task {:?} changed: {}", task.name, decision);
+        Ok(decision)
     }
     fn check_outputs(
         &mut self,
@@ -76,7 +184,8 @@ impl TaskTriggerChecker for NaiveTriggerChecker {
         let output_hashes = context;
 
         let newest_source_timestamp = newest_input_timestamp(task, &self.not_changed)
-            .map_err(OutputCheckError::InputTimestampError)?;
+            .map_err(OutputCheckError::InputTimestampError)?
+            .map(|(timestamp, _)| timestamp);
 
         for path in task.resolve_outputs() {
             let path: &Path = path.as_ref();
@@ -162,32 +271,35 @@ fn sources_changed(
     task: &InstantiatedTask,
     output_hashes: &mut HashMap<PathBuf, Hash>,
     not_changed: &HashMap<PathBuf, bool>,
-) -> Result<bool, SourceChangeCheckError> {
-    let newest_source_timestamp = newest_input_timestamp(task, not_changed)
+) -> Result<TriggerDecision, SourceChangeCheckError> {
+    let newest_source = newest_input_timestamp(task, not_changed)
         .map_err(SourceChangeCheckError::InputTimestampError)?;
-    log::trace!("Newest source timestamp for task {:?}: {:?}", task.name, newest_source_timestamp.map(|t| chrono::DateTime::<chrono::Utc>::from(t)));
+    log::trace!("Newest source timestamp for task {:?}: {:?}", task.name, newest_source.as_ref().map(|(t, _)| chrono::DateTime::<chrono::Utc>::from(*t)));
 
     // check all output files against the source file timestamp
-    let mut changed = false;
+    let mut reason = None;
     for path in task.resolve_outputs() {
         let path: &Path = path.as_ref();
         if !path.exists() {
             // If the output file does not exist, we need certainly to run the task.
             log::info!("Output file {path:?} does not exist, task {:?} needs to run", task.name);
-            changed = true;
+            reason.get_or_insert_with(|| RunReason::OutputMissing(path.to_path_buf()));
             continue;
         }
         let metadata = std::fs::metadata(path)
             .map_err(|e| SourceChangeCheckError::OutputTimestampError(path.to_path_buf(), e))?;
-        if let Some(newest_source_timestamp) = newest_source_timestamp {
+        if let Some((newest_source_timestamp, newest_source)) = &newest_source {
             let output_timestamp = metadata
                 .modified()
                 .expect("Failed to get modified time for output file");
             log::trace!("Output timestamp for task {:?}: {:?}", task.name, chrono::DateTime::<chrono::Utc>::from(output_timestamp));
-            if output_timestamp < newest_source_timestamp {
+            if output_timestamp < *newest_source_timestamp {
                 // If the output file is older than the newest source file,
                 // dependencies have changed.
-                changed = true;
+                reason.get_or_insert_with(|| RunReason::SourceNewer {
+                    source: newest_source.clone(),
+                    output: path.to_path_buf(),
+                });
             }
         };
 
@@ -199,7 +311,11 @@ fn sources_changed(
         }
     }
 
-    Ok(changed || task.body.phony)
+    if task.body.phony {
+        reason.get_or_insert(RunReason::Phony);
+    }
+
+    Ok(reason.map_or(TriggerDecision::Skip(SkipReason::UpToDate), TriggerDecision::Run))
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -217,10 +333,10 @@ pub enum SourceChangeCheckError {
 fn newest_input_timestamp(
     task: &InstantiatedTask,
     not_changed: &HashMap<PathBuf, bool>,
-) -> anyhow::Result<Option<SystemTime>> {
+) -> anyhow::Result<Option<(SystemTime, PathBuf)>> {
     let mut newest_source_timestamp = None;
 
-    log::trace!("Checking sources for task {:?}: {:?}", task.name, task.resolve_sources().collect::<Vec<_>>());
+    log::trace!("Checking sources for task {:?}: {:?}", task.name, task.resolve_sources());
     for path in task.resolve_sources() {
         let path: &Path = path.as_ref();
 
@@ -236,12 +352,12 @@ fn newest_input_timestamp(
         let metadata = std::fs::metadata(path)?;
         let timestamp = metadata.modified()?;
 
-        if let Some(oldest) = newest_source_timestamp {
+        if let Some((oldest, _)) = newest_source_timestamp {
             if timestamp > oldest {
-                newest_source_timestamp = Some(timestamp);
+                newest_source_timestamp = Some((timestamp, path.to_path_buf()));
             }
         } else {
-            newest_source_timestamp = Some(timestamp);
+            newest_source_timestamp = Some((timestamp, path.to_path_buf()));
         }
     }
 
@@ -0,0 +1,796 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap}, fs, io::ErrorKind, path::{Path, PathBuf}, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    command::Command,
+    run::{execution::triggers::{hash_file, FileHashingError, NaiveTriggerChecker, OutputCheckError, RecipePart, RunError, RunReason, SkipReason, TaskTriggerChecker, TriggerDecision}, trace},
+    task::{InstantiatedTask, ResolvedTaskInvocation},
+};
+
+/// Bumped every time the format of the state file changes
+const STATE_VERSION: u32 = 3;
+
+/// Files modified this close to the time they were hashed may be rewritten
+/// again without changing their mtime, 2 seconds is the coarsest granularity
+/// of the common filesystems (FAT)
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// Trigger checker that remembers, across runs, the content of the sources and
+/// outputs of every task invocation
+///
+/// A task is up-to-date if its sources, its outputs and its recipe (the rendered
+/// steps, environment, workdir and arguments) have the same content as the last
+/// time it was checked, regardless of the timestamps. There is one record per
+/// invocation, so a task invoked with different arguments is tracked separately
+/// for each of them. Tasks that were never recorded fall back to the
+/// timestamp-based [`NaiveTriggerChecker`].
+///
+/// The duration of the last successful run of each invocation is also kept, to
+/// schedule the longest tasks first.
+///
+/// The new records are written when the checker is saved or dropped, merged
+/// with the records other processes saved in the meantime.
+///
+/// Clones share the same records, so tasks running in parallel can check their
+/// triggers at the same time. The locks are only held to read and write the
+/// records, the files are hashed outside of them.
+#[derive(Debug, Clone)]
+pub struct PersistentTriggerChecker {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    path: PathBuf,
+    records: Mutex<Records>,
+    /// Last known state of each file, used to avoid hashing files that did not change
+    known_files: Mutex<HashMap<PathBuf, FileState>>,
+    fallback: Mutex<NaiveTriggerChecker>,
+}
+
+#[derive(Debug)]
+struct Records {
+    state: BuildState,
+    /// The records updated since the last save
+    changes: BuildState,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BuildState {
+    version: u32,
+    tasks: BTreeMap<String, TaskState>,
+    /// Duration in milliseconds of the last run of each invocation
+    #[serde(default)]
+    durations: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TaskState {
+    recipe: Recipe,
+    sources: BTreeMap<PathBuf, FileState>,
+    outputs: BTreeMap<PathBuf, FileState>,
+}
+
+/// Hashes of the parts of the rendered task
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Recipe {
+    steps: String,
+    env: String,
+    workdir: String,
+    args: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FileState {
+    hash: String,
+    size: u64,
+    mtime_ns: u64,
+    /// When the file was hashed, in nanoseconds since the epoch
+    hashed_ns: u64,
+}
+
+pub struct PersistentTaskContext {
+    /// Key of the task, shared by all its invocations
+    task: String,
+    invocation: String,
+    started: Instant,
+    fallback: <NaiveTriggerChecker as TaskTriggerChecker>::TaskContext,
+    /// Whether the decision was taken by the fallback, which then checks the outputs too
+    used_fallback: bool,
+}
+
+impl PersistentTriggerChecker {
+    /// Loads the state stored in the given directory, an invalid or missing state is ignored
+    pub fn load(dir: impl AsRef<Path>) -> Self {
+        let path = dir.as_ref().join("state");
+        let state = BuildState::read(&path);
+
+        let known_files = state
+            .tasks
+            .values()
+            .flat_map(|task| task.sources.iter().chain(&task.outputs))
+            .map(|(path, file)| (path.clone(), file.clone()))
+            .collect();
+
+        Self {
+            shared: Arc::new(Shared {
+                path,
+                records: Mutex::new(Records {
+                    state,
+                    changes: BuildState::default(),
+                }),
+                known_files: Mutex::new(known_files),
+                fallback: Mutex::new(NaiveTriggerChecker::default()),
+            }),
+        }
+    }
+
+    /// Writes the records updated since the last save
+    ///
+    /// The state is read again and only the updated records are replaced, so that
+    /// the records saved by other runs sharing the state directory are kept.
+    pub fn save(&self) -> Result<(), PersistentCheckError> {
+        self.shared.save()
+    }
+
+    /// Returns the current state of a file, hashing it only if it changed since we last saw it
+    ///
+    /// A file could be rewritten with the same size within the granularity of
+    /// the timestamps, so like git does with its index, the known state is only
+    /// trusted if the file was modified strictly before it was hashed, by at
+    /// least [`RACY_WINDOW`].
+    ///
+    /// The mtime of a directory doesn't change when a file deep inside it is
+    /// rewritten, so directories are always hashed again, from the state of
+    /// each of their files.
+    fn file_state(&self, path: &Path) -> Result<FileState, PersistentCheckError> {
+        let metadata = fs::metadata(path).map_err(|e| PersistentCheckError::MetadataError(path.to_path_buf(), e))?;
+        if metadata.is_dir() {
+            return Ok(FileState {
+                hash: self.hash_dir(path)?,
+                size: 0,
+                mtime_ns: 0,
+                hashed_ns: 0,
+            });
+        }
+
+        let size = metadata.len();
+        let mtime_ns = mtime_ns(&metadata);
+
+        let unchanged = |known: &&FileState| {
+            known.size == size
+                && known.mtime_ns == mtime_ns
+                && mtime_ns.saturating_add(RACY_WINDOW.as_nanos() as u64) < known.hashed_ns
+        };
+        if let Some(known) = self.shared.known_files.lock().unwrap().get(path).filter(unchanged) {
+            return Ok(known.clone());
+        }
+
+        let hashed_ns = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        let mut span = trace::span(|| "hash".to_string(), "hash");
+        span.arg("path", || path.display().to_string().into());
+        let hash = hash_file(path).map_err(|e| PersistentCheckError::HashingError(path.to_path_buf(), e))?;
+        drop(span);
+        let state = FileState { hash: to_hex(&hash), size, mtime_ns, hashed_ns };
+        self.shared.known_files.lock().unwrap().insert(path.to_path_buf(), state.clone());
+        Ok(state)
+    }
+
+    /// Hashes the names and the content of the entries of a directory
+    ///
+    /// Symbolic links are not followed, their target is hashed instead: links
+    /// may dangle or point back to a parent directory.
+    fn hash_dir(&self, path: &Path) -> Result<String, PersistentCheckError> {
+        let read_error = |e| PersistentCheckError::MetadataError(path.to_path_buf(), e);
+        let mut entries = fs::read_dir(path)
+            .map_err(read_error)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(read_error)?;
+        entries.sort();
+
+        let mut hasher = Sha256::new();
+        for entry in entries {
+            hasher.update(entry.file_name().unwrap_or_default().as_encoded_bytes());
+            let metadata = fs::symlink_metadata(&entry).map_err(|e| PersistentCheckError::MetadataError(entry.clone(), e))?;
+            if metadata.is_symlink() {
+                let target = fs::read_link(&entry).map_err(|e| PersistentCheckError::MetadataError(entry.clone(), e))?;
+                hasher.update(b"link:");
+                hasher.update(target.as_os_str().as_encoded_bytes());
+            } else {
+                hasher.update(self.file_state(&entry)?.hash);
+            }
+        }
+        Ok(to_hex(&hasher.finalize()))
+    }
+
+    /// Duration of the last successful run of the invocation, if known
+    pub fn expected_duration(&self, invocation: &ResolvedTaskInvocation) -> Option<Duration> {
+        let records = self.shared.records.lock().unwrap();
+        records.state.durations.get(&Self::invocation_key(invocation)).copied().map(Duration::from_millis)
+    }
+
+    fn invocation_key(invocation: &ResolvedTaskInvocation) -> String {
+        format!("{} {}", Self::task_key(invocation), serde_json::to_string(&invocation.args).expect("Failed to serialize arguments"))
+    }
+
+    fn task_key(invocation: &ResolvedTaskInvocation) -> String {
+        invocation.r#ref.display_absolute().to_string()
+    }
+
+    fn is_tracked(task: &InstantiatedTask) -> bool {
+        task.resolve_outputs().next().is_some() && !task.body.steps.is_empty()
+    }
+
+    /// Lets the timestamp-based fallback decide, on a snapshot of it so that it
+    /// doesn't hold the lock while hashing
+    fn fallback_should_run(&self, task: &InstantiatedTask, context: &mut PersistentTaskContext) -> Result<TriggerDecision, PersistentCheckError> {
+        context.used_fallback = true;
+        let mut fallback = self.shared.fallback.lock().unwrap().clone();
+        Ok(fallback.should_run(task, &mut context.fallback)?)
+    }
+}
+
+impl TaskTriggerChecker for PersistentTriggerChecker {
+    type TaskContext = PersistentTaskContext;
+    type RunError = PersistentCheckError;
+    type OutputCheckError = PersistentCheckError;
+
+    fn new_task_context(&mut self, invocation: &ResolvedTaskInvocation) -> Self::TaskContext {
+        PersistentTaskContext {
+            task: Self::task_key(invocation),
+            invocation: Self::invocation_key(invocation),
+            started: Instant::now(),
+            fallback: self.shared.fallback.lock().unwrap().new_task_context(invocation),
+            used_fallback: false,
+        }
+    }
+
+    fn should_run(&mut self, task: &InstantiatedTask, context: &mut Self::TaskContext) -> Result<TriggerDecision, Self::RunError> {
+        if !Self::is_tracked(task) || task.body.phony {
+            // the state does not matter for these
+            return self.fallback_should_run(task, context);
+        }
+
+        let (previous, other_invocations) = {
+            let records = self.shared.records.lock().unwrap();
+            (records.state.tasks.get(&context.invocation).cloned(), records.state.has_other_invocations(context))
+        };
+        let Some(previous) = previous else {
+            // the outputs, if any, were written with other arguments
+            if other_invocations {
+                return Ok(TriggerDecision::Run(RunReason::RecipeChanged(RecipePart::Args)));
+            }
+            log::trace!("No previous state for task {:?}, checking timestamps", task.name);
+            return self.fallback_should_run(task, context);
+        };
+
+        for output in task.resolve_outputs() {
+            let output: &Path = output.as_ref();
+            if !output.exists() {
+                return Ok(TriggerDecision::Run(RunReason::OutputMissing(output.to_path_buf())));
+            }
+        }
+
+        if let Some(part) = previous.recipe.changed_part(&Recipe::of(task)) {
+            return Ok(TriggerDecision::Run(RunReason::RecipeChanged(part)));
+        }
+
+        let sources = task.resolve_sources().iter().cloned().collect::<BTreeSet<_>>();
+        if let Some(source) = sources.symmetric_difference(&previous.sources.keys().cloned().collect()).next() {
+            return Ok(TriggerDecision::Run(RunReason::SourceChanged(source.clone())));
+        }
+        for source in sources {
+            if self.file_state(&source)?.hash != previous.sources[&source].hash {
+                return Ok(TriggerDecision::Run(RunReason::SourceChanged(source)));
+            }
+        }
+
+        // globs can match a different set of files
+        let outputs = task.resolve_outputs().map(|output| output.as_ref().to_path_buf()).collect::<BTreeSet<_>>();
+        if let Some(output) = outputs.symmetric_difference(&previous.outputs.keys().cloned().collect()).next() {
+            if !output.exists() {
+                return Ok(TriggerDecision::Run(RunReason::OutputMissing(output.clone())));
+            }
+            return Ok(TriggerDecision::Run(RunReason::OutputChanged(output.clone())));
+        }
+        for output in outputs {
+            if self.file_state(&output)?.hash != previous.outputs[&output].hash {
+                return Ok(TriggerDecision::Run(RunReason::OutputChanged(output)));
+            }
+        }
+
+        Ok(TriggerDecision::Skip(SkipReason::UpToDate))
+    }
+
+    fn check_outputs(&mut self, task: &InstantiatedTask, context: &mut Self::TaskContext, executed: bool) -> Result<(), Self::OutputCheckError> {
+        if executed {
+            let duration = context.started.elapsed().as_millis() as u64;
+            let mut records = self.shared.records.lock().unwrap();
+            records.state.durations.insert(context.invocation.clone(), duration);
+            records.changes.durations.insert(context.invocation.clone(), duration);
+        }
+
+        if context.used_fallback {
+            // keeps track of the outputs that did not change, for the dependents it checks
+            let mut fallback = self.shared.fallback.lock().unwrap().clone();
+            fallback.check_outputs(task, &mut context.fallback, executed)?;
+            self.shared.fallback.lock().unwrap().merge(fallback);
+        }
+
+        if !Self::is_tracked(task) {
+            return Ok(());
+        }
+
+        let mut outputs = BTreeMap::new();
+        for output in task.resolve_outputs() {
+            let output: &Path = output.as_ref();
+            if !output.exists() {
+                return Err(PersistentCheckError::OutputFileNotFound(output.to_path_buf()));
+            }
+            outputs.insert(output.to_path_buf(), self.file_state(output)?);
+        }
+
+        let sources = task
+            .resolve_sources()
+            .iter()
+            .map(|source| Ok((source.clone(), self.file_state(source)?)))
+            .collect::<Result<_, PersistentCheckError>>()?;
+
+        let record = TaskState {
+            recipe: Recipe::of(task),
+            sources,
+            outputs,
+        };
+        let mut records = self.shared.records.lock().unwrap();
+        records.state.tasks.insert(context.invocation.clone(), record.clone());
+        records.changes.tasks.insert(context.invocation.clone(), record);
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn save(&self) -> Result<(), PersistentCheckError> {
+        static SAVES: AtomicUsize = AtomicUsize::new(0);
+
+        let changes = {
+            let mut records = self.records.lock().unwrap();
+            if records.changes.tasks.is_empty() && records.changes.durations.is_empty() {
+                return Ok(());
+            }
+            std::mem::take(&mut records.changes)
+        };
+        let save_error = |e| PersistentCheckError::SaveError(self.path.clone(), e);
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(save_error)?;
+        }
+
+        let mut state = BuildState::read(&self.path);
+        state.tasks.extend(changes.tasks);
+        state.durations.extend(changes.durations);
+
+        // write and rename, so that an interrupted run does not leave a truncated file
+        let tmp = self.path.with_extension(format!("{}.{}.tmp", std::process::id(), SAVES.fetch_add(1, Ordering::Relaxed)));
+        let data = serde_json::to_vec(&state).expect("Failed to serialize build state");
+        fs::write(&tmp, data).map_err(save_error)?;
+        fs::rename(&tmp, &self.path).map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            save_error(e)
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for Shared {
+    /// Saves the records of an interrupted or cancelled run too, once the last clone is dropped
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            log::warn!("{e}");
+        }
+    }
+}
+
+impl BuildState {
+    /// Whether the task was recorded with arguments other than the ones of the context
+    fn has_other_invocations(&self, context: &PersistentTaskContext) -> bool {
+        // the arguments are serialized as an object
+        let prefix = format!("{} {{", context.task);
+        self.tasks.keys().any(|key| key.starts_with(&prefix) && *key != context.invocation)
+    }
+
+    /// Reads the state from the given file, an invalid or missing state gives an empty state
+    fn read(path: &Path) -> Self {
+        let state = match fs::read(path) {
+            Ok(data) => match serde_json::from_slice::<BuildState>(&data) {
+                Ok(state) if state.version == STATE_VERSION => state,
+                Ok(_) => {
+                    log::info!("Ignoring build state {} from a different version", path.display());
+                    BuildState::default()
+                }
+                Err(e) => {
+                    log::warn!("Ignoring invalid build state {}: {e}", path.display());
+                    BuildState::default()
+                }
+            },
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    log::warn!("Failed to read build state {}: {e}", path.display());
+                }
+                BuildState::default()
+            }
+        };
+        BuildState {
+            version: STATE_VERSION,
+            ..state
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PersistentCheckError {
+    #[error("{0}")]
+    FallbackError(#[from] RunError),
+    #[error("{0}")]
+    FallbackOutputError(#[from] OutputCheckError),
+    #[error("Failed to read metadata of {0}: {1}")]
+    MetadataError(PathBuf, std::io::Error),
+    #[error("Failed to hash {0}: {1}")]
+    HashingError(PathBuf, FileHashingError),
+    #[error("Output file {0} does not exist after running task")]
+    OutputFileNotFound(PathBuf),
+    #[error("Failed to save build state to {0}: {1}")]
+    SaveError(PathBuf, std::io::Error),
+}
+
+impl Recipe {
+    fn of(task: &InstantiatedTask) -> Self {
+        let hash = |parts: &mut dyn Iterator<Item = String>| {
+            let mut hasher = Sha256::new();
+            for part in parts {
+                hasher.update(part);
+                hasher.update([0]);
+            }
+            to_hex(&hasher.finalize())
+        };
+
+        Self {
+            steps: hash(&mut task.body.steps.iter().map(|step| match step {
+                Command::Shell(cmd) => format!("shell:{cmd}"),
+            })),
+            env: hash(&mut task.body.env.iter().map(|(key, value)| format!("{key}={value}"))),
+            workdir: hash(&mut std::iter::once(task.body.workdir.to_string_lossy().into_owned())),
+            args: hash(&mut task.args.iter().map(|(key, value)| format!("{key}={value}"))),
+        }
+    }
+
+    fn changed_part(&self, other: &Recipe) -> Option<RecipePart> {
+        // the arguments are checked first, the other parts are usually rendered from them
+        [
+            (RecipePart::Args, &self.args, &other.args),
+            (RecipePart::Workdir, &self.workdir, &other.workdir),
+            (RecipePart::Env, &self.env, &other.env),
+            (RecipePart::Steps, &self.steps, &other.steps),
+        ]
+        .into_iter()
+        .find(|(_, a, b)| a != b)
+        .map(|(part, _, _)| part)
+    }
+}
+
+/// Modification time in nanoseconds since the epoch, 0 if unknown
+fn mtime_ns(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use serde_json::{json, Value as Json};
+
+    use crate::task::{ArgType, OutputPath, Param, ResolvedRef, Task, TaskInvocation, TaskfileId};
+
+    use super::*;
+
+    /// Writes `content` to `path` with the given mtime, in seconds since the epoch
+    fn write(path: &Path, content: &str, mtime: u64) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))
+            .unwrap();
+    }
+
+    /// A file rewritten deep inside a directory changes the state of the directory
+    #[test]
+    fn directory_content_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let file = out.join("sub").join("a.o");
+        let mut checker = PersistentTriggerChecker::load(dir.path().join(".birb"));
+
+        write(&file, "aaa", 1_000);
+        let before = checker.file_state(&out).unwrap();
+        assert_eq!(checker.file_state(&out).unwrap().hash, before.hash);
+
+        // same size, the directories keep their mtime
+        write(&file, "bbb", 2_000);
+        assert_ne!(checker.file_state(&out).unwrap().hash, before.hash);
+
+        write(&file, "aaa", 3_000);
+        assert_eq!(checker.file_state(&out).unwrap().hash, before.hash);
+    }
+
+    /// A dangling link inside a directory is hashed by its target
+    #[cfg(unix)]
+    #[test]
+    fn directory_with_dangling_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        write(&out.join("a.o"), "aaa", 1_000);
+        let mut checker = PersistentTriggerChecker::load(dir.path().join(".birb"));
+
+        std::os::unix::fs::symlink("missing.o", out.join("link")).unwrap();
+        let before = checker.file_state(&out).unwrap();
+        assert_eq!(checker.file_state(&out).unwrap().hash, before.hash);
+
+        fs::remove_file(out.join("link")).unwrap();
+        std::os::unix::fs::symlink("other.o", out.join("link")).unwrap();
+        assert_ne!(checker.file_state(&out).unwrap().hash, before.hash);
+    }
+
+    /// A link back to a parent directory is not followed, following it would never end
+    #[cfg(unix)]
+    #[test]
+    fn directory_with_symlink_to_parent() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        write(&out.join("sub").join("a.o"), "aaa", 1_000);
+        let mut checker = PersistentTriggerChecker::load(dir.path().join(".birb"));
+
+        std::os::unix::fs::symlink("..", out.join("sub").join("parent")).unwrap();
+        let before = checker.file_state(&out).unwrap();
+
+        write(&out.join("sub").join("a.o"), "bbb", 2_000);
+        assert_ne!(checker.file_state(&out).unwrap().hash, before.hash);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().join(".birb");
+        let source = dir.path().join("main.c");
+        write(&source, "int main() {}", 1_000);
+
+        let mut checker = PersistentTriggerChecker::load(&state_dir);
+        let file = checker.file_state(&source).unwrap();
+        let recipe = Recipe {
+            steps: "steps".to_string(),
+            env: "env".to_string(),
+            workdir: "workdir".to_string(),
+            args: "args".to_string(),
+        };
+        checker.shared.records.lock().unwrap().changes.tasks.insert("task".to_string(), TaskState {
+            recipe: recipe.clone(),
+            sources: [(source.clone(), file.clone())].into_iter().collect(),
+            outputs: BTreeMap::new(),
+        });
+        checker.shared.records.lock().unwrap().changes.durations.insert("task {}".to_string(), 42);
+        checker.save().unwrap();
+
+        let loaded = PersistentTriggerChecker::load(&state_dir);
+        let records = loaded.shared.records.lock().unwrap();
+        assert_eq!(records.state.version, STATE_VERSION);
+        let task = &records.state.tasks["task"];
+        assert_eq!(task.recipe, recipe);
+        assert_eq!(task.sources[&source].hash, file.hash);
+        assert_eq!(task.sources[&source].size, file.size);
+        assert_eq!(task.sources[&source].mtime_ns, 1_000_000_000_000);
+        assert_eq!(records.state.durations["task {}"], 42);
+        // the recorded files are not hashed again
+        assert!(loaded.shared.known_files.lock().unwrap().contains_key(&source));
+
+        // a state from another version is ignored
+        let mut data = serde_json::from_slice::<Json>(&fs::read(state_dir.join("state")).unwrap()).unwrap();
+        data["version"] = Json::from(STATE_VERSION - 1);
+        fs::write(state_dir.join("state"), serde_json::to_vec(&data).unwrap()).unwrap();
+        assert!(PersistentTriggerChecker::load(&state_dir).shared.records.lock().unwrap().state.tasks.is_empty());
+    }
+
+    /// A file rewritten with the same size and mtime as recorded is hashed again,
+    /// unless it was modified well before it was hashed
+    #[test]
+    fn racy_files_are_hashed_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().join(".birb");
+        let recent = dir.path().join("recent.c");
+        let old = dir.path().join("old.c");
+        fs::write(&recent, "aaa").unwrap();
+        let recent_mtime = fs::metadata(&recent).unwrap().modified().unwrap();
+        write(&old, "aaa", 2_000);
+
+        let mut checker = PersistentTriggerChecker::load(&state_dir);
+        let recorded = checker.file_state(&recent).unwrap();
+        let old_state = checker.file_state(&old).unwrap();
+        checker.shared.records.lock().unwrap().changes.tasks.insert("task".to_string(), TaskState {
+            recipe: Recipe {
+                steps: String::new(),
+                env: String::new(),
+                workdir: String::new(),
+                args: String::new(),
+            },
+            sources: [
+                (recent.clone(), recorded.clone()),
+                (old.clone(), old_state),
+            ].into_iter().collect(),
+            outputs: BTreeMap::new(),
+        });
+        // the state is saved later, e.g. at the end of a long run
+        checker.save().unwrap();
+
+        // edited right after it was hashed, within the same timestamp
+        fs::write(&recent, "bbb").unwrap();
+        File::options().write(true).open(&recent).unwrap().set_modified(recent_mtime).unwrap();
+        // same size and mtime, but modified long before it was hashed, the record is trusted
+        write(&old, "bbb", 2_000);
+
+        let mut checker = PersistentTriggerChecker::load(&state_dir);
+        assert_ne!(checker.file_state(&recent).unwrap().hash, recorded.hash);
+        let old_recorded = checker.shared.records.lock().unwrap().state.tasks["task"].sources[&old].hash.clone();
+        assert_eq!(checker.file_state(&old).unwrap().hash, old_recorded);
+    }
+
+    /// Each set of arguments has its own record, running one doesn't invalidate the other
+    #[test]
+    fn invocations_are_tracked_separately() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut task = Task::new("build");
+        task.params.insert("mode".to_string(), Param { ty: ArgType::String, default: None });
+        task.body.workdir = dir.path().to_path_buf();
+        task.body.steps = vec![Command::Shell("build {{args.mode}}".to_string())];
+        task.body.outputs.paths = vec![OutputPath::new("out-{{args.mode}}.txt")];
+
+        let invocation = |mode: &str| TaskInvocation {
+            r#ref: ResolvedRef {
+                taskfile: TaskfileId::from_path(dir.path().join("tasks.yaml")),
+                name: "build".to_string(),
+            },
+            args: BTreeMap::from([("mode".to_string(), json!(mode))]),
+        };
+        let mut checker = PersistentTriggerChecker::load(dir.path().join(".birb"));
+        let check = |checker: &mut PersistentTriggerChecker, mode: &str| {
+            let invocation = invocation(mode);
+            let task = task.instantiate(&invocation.args, &BTreeMap::new()).unwrap();
+            let mut context = checker.new_task_context(&invocation);
+            let decision = checker.should_run(&task, &mut context).unwrap();
+            if decision.should_run() {
+                write(&dir.path().join(format!("out-{mode}.txt")), mode, 1_000);
+                checker.check_outputs(&task, &mut context, true).unwrap();
+            }
+            decision
+        };
+
+        assert!(check(&mut checker, "debug").should_run());
+        assert!(check(&mut checker, "release").should_run());
+        assert_eq!(check(&mut checker, "debug"), TriggerDecision::Skip(SkipReason::UpToDate));
+        assert_eq!(check(&mut checker, "release"), TriggerDecision::Skip(SkipReason::UpToDate));
+    }
+
+    /// Outputs that don't depend on the arguments are rebuilt when the arguments change
+    #[test]
+    fn changed_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("main.c");
+        write(&source, "int main() {}", 1_000);
+        let mut task = Task::new("build");
+        task.params.insert("mode".to_string(), Param { ty: ArgType::String, default: None });
+        task.body.workdir = dir.path().to_path_buf();
+        task.body.steps = vec![Command::Shell("cc --{{args.mode}} main.c -o app".to_string())];
+        task.body.sources = vec!["main.c".to_string()];
+        task.body.outputs.paths = vec![OutputPath::new("app")];
+
+        let invocation = |mode: &str| TaskInvocation {
+            r#ref: ResolvedRef {
+                taskfile: TaskfileId::from_path(dir.path().join("tasks.yaml")),
+                name: "build".to_string(),
+            },
+            args: BTreeMap::from([("mode".to_string(), json!(mode))]),
+        };
+        let mut checker = PersistentTriggerChecker::load(dir.path().join(".birb"));
+        let mut check = |mode: &str, mtime: u64| {
+            let invocation = invocation(mode);
+            let task = task.instantiate(&invocation.args, &BTreeMap::new()).unwrap();
+            let mut context = checker.new_task_context(&invocation);
+            let decision = checker.should_run(&task, &mut context).unwrap();
+            if decision.should_run() {
+                write(&dir.path().join("app"), mode, mtime);
+                checker.check_outputs(&task, &mut context, true).unwrap();
+            }
+            decision
+        };
+
+        assert!(check("debug", 2_000).should_run());
+        assert_eq!(check("debug", 3_000), TriggerDecision::Skip(SkipReason::UpToDate));
+        assert_eq!(check("release", 4_000), TriggerDecision::Run(RunReason::RecipeChanged(RecipePart::Args)));
+        assert_eq!(check("release", 5_000), TriggerDecision::Skip(SkipReason::UpToDate));
+        // the output of debug was overwritten
+        assert_eq!(check("debug", 6_000), TriggerDecision::Run(RunReason::OutputChanged(dir.path().join("app"))));
+    }
+
+    /// Without a record, the outputs are checked by the fallback too
+    #[test]
+    fn fallback_checks_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.txt");
+        let mut task = Task::new("build");
+        task.body.workdir = dir.path().to_path_buf();
+        task.body.steps = vec![Command::Shell("build".to_string())];
+        task.body.outputs.paths = vec![OutputPath::new("out.txt")];
+        let task = task.instantiate(&BTreeMap::new(), &BTreeMap::new()).unwrap();
+        let invocation = TaskInvocation {
+            r#ref: ResolvedRef {
+                taskfile: TaskfileId::from_path(dir.path().join("tasks.yaml")),
+                name: "build".to_string(),
+            },
+            args: BTreeMap::new(),
+        };
+
+        let mut checker = PersistentTriggerChecker::load(dir.path().join(".birb"));
+        let mut context = checker.new_task_context(&invocation);
+        assert_eq!(checker.should_run(&task, &mut context).unwrap(), TriggerDecision::Run(RunReason::OutputMissing(output.clone())));
+        write(&output, "out", 1_000);
+        checker.check_outputs(&task, &mut context, true).unwrap();
+
+        assert!(checker.shared.fallback.lock().unwrap().not_changed.contains_key(&output));
+        assert!(checker.shared.records.lock().unwrap().state.tasks.contains_key(&context.invocation));
+    }
+
+    /// Runs sharing the state directory keep the records of each other
+    #[test]
+    fn concurrent_saves_are_merged() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().join(".birb");
+        let recipe = Recipe {
+            steps: String::new(),
+            env: String::new(),
+            workdir: String::new(),
+            args: String::new(),
+        };
+        let record = || TaskState {
+            recipe: recipe.clone(),
+            sources: BTreeMap::new(),
+            outputs: BTreeMap::new(),
+        };
+
+        let mut first = PersistentTriggerChecker::load(&state_dir);
+        let mut second = PersistentTriggerChecker::load(&state_dir);
+        first.shared.records.lock().unwrap().changes.tasks.insert("a".to_string(), record());
+        first.shared.records.lock().unwrap().changes.durations.insert("a".to_string(), 1);
+        second.shared.records.lock().unwrap().changes.tasks.insert("b".to_string(), record());
+        first.save().unwrap();
+        drop(second);
+
+        let loaded = PersistentTriggerChecker::load(&state_dir);
+        let records = loaded.shared.records.lock().unwrap();
+        assert_eq!(records.state.tasks.keys().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(records.state.durations["a"], 1);
+        // only the state itself is left
+        assert_eq!(fs::read_dir(&state_dir).unwrap().count(), 1);
+    }
+}
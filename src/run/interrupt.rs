@@ -0,0 +1,111 @@
+use std::sync::{atomic::{AtomicU8, Ordering}, Arc, OnceLock};
+
+use tokio::sync::Notify;
+
+/// Shared flag used to ask a run to stop
+///
+/// An interrupt can have a parent, in which case it is also considered
+/// triggered when the parent is. This allows to stop a single run (e.g. in
+/// watch mode) while still reacting to Ctrl-C.
+///
+/// Triggering an interrupt more than once escalates it: the first time running
+/// steps are asked to stop and given some time to exit, the second time they are
+/// killed.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt {
+    state: Arc<State>,
+    parent: Option<Arc<Interrupt>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    level: AtomicU8,
+    /// Woken up every time the interrupt is triggered
+    triggered: Notify,
+}
+
+impl Interrupt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the process-wide interrupt triggered by Ctrl-C
+    ///
+    /// The signal handler is installed the first time this is called.
+    pub fn ctrl_c() -> Self {
+        static CTRL_C: OnceLock<Interrupt> = OnceLock::new();
+        CTRL_C
+            .get_or_init(|| {
+                let interrupt = Interrupt::new();
+                let i = interrupt.clone();
+                ctrlc::set_handler(move || {
+                    i.trigger();
+                    if i.is_forced() {
+                        eprintln!("Interrupted again, killing running steps");
+                    } else {
+                        eprintln!("Interrupted, waiting for running steps to stop (press Ctrl-C again to kill them)");
+                    }
+                })
+                .expect("Failed to set Ctrl-C handler");
+                interrupt
+            })
+            .clone()
+    }
+
+    /// Creates a new interrupt that is also triggered when this one is
+    pub fn child(&self) -> Self {
+        Self {
+            state: Default::default(),
+            parent: Some(Arc::new(self.clone())),
+        }
+    }
+
+    /// Triggers the interrupt, or escalates it if it was already triggered
+    pub fn trigger(&self) {
+        let _ = self.state.level.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |level| Some(level.saturating_add(1)));
+        self.state.triggered.notify_waiters();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.level() >= 1
+    }
+
+    /// Returns `true` if the interrupt was triggered more than once, running steps should be killed
+    pub fn is_forced(&self) -> bool {
+        self.level() >= 2
+    }
+
+    /// Waits until the interrupt is triggered
+    pub async fn triggered(&self) {
+        self.reached(1).await
+    }
+
+    /// Waits until the interrupt is triggered more than once
+    pub async fn forced(&self) {
+        self.reached(2).await
+    }
+
+    async fn reached(&self, level: u8) {
+        loop {
+            // registered before checking the level, so that a trigger in between is not missed
+            let notified = self.state.triggered.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.level() >= level {
+                return;
+            }
+            match &self.parent {
+                Some(parent) => tokio::select! {
+                    _ = notified => {},
+                    _ = Box::pin(parent.reached(level)) => return,
+                },
+                None => notified.await,
+            }
+        }
+    }
+
+    fn level(&self) -> u8 {
+        let level = self.state.level.load(Ordering::SeqCst);
+        self.parent.as_ref().map_or(level, |p| level.max(p.level()))
+    }
+}
@@ -0,0 +1,175 @@
+use std::{future::Future, io, sync::{Arc, Mutex, OnceLock}, time::Duration};
+
+use jobserver::{Acquired, Client, HelperThread};
+use tokio::sync::Notify;
+
+/// How long [`Jobserver::changed`] waits at most, so the waiter can check for interrupts
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// GNU make compatible jobserver, shared with the nested builds (`make`, `cargo`,
+/// another `birb`) started by the steps
+///
+/// As in make, the process owns one implicit token and every other running
+/// task needs a token from the jobserver, so the tasks and the jobs of the
+/// nested builds together never exceed the limit. The scheduler takes a token
+/// before starting a task, so the tasks still start in its order.
+#[derive(Debug, Clone)]
+pub struct Jobserver(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    client: Client,
+    /// Acquires the tokens in the background, so the scheduler never blocks on the pipe
+    helper: HelperThread,
+    tokens: Arc<Tokens>,
+}
+
+#[derive(Debug, Default)]
+struct Tokens {
+    state: Mutex<TokensState>,
+    /// Notified when a token arrives or a task stops
+    changed: Notify,
+}
+
+#[derive(Debug, Default)]
+struct TokensState {
+    /// Tasks holding a token, the first one holds the implicit token
+    running: usize,
+    /// Tokens of the running tasks but the first one, returned first to the jobserver
+    held: Vec<Acquired>,
+    /// Tokens acquired by the helper thread, not yet given to a task
+    ready: Vec<Acquired>,
+    error: Option<io::Error>,
+    /// Requests sent to the helper thread whose token didn't arrive yet
+    requested: usize,
+    /// Requests nobody waits for anymore, their tokens are returned as soon as they arrive
+    surplus: usize,
+}
+
+/// A token held by a running task, released when dropped
+#[derive(Debug)]
+pub struct JobToken(Jobserver);
+
+static INHERITED: OnceLock<Option<Jobserver>> = OnceLock::new();
+
+tokio::task_local! {
+    /// The jobserver of the current task
+    static CURRENT: Jobserver;
+}
+
+impl Jobserver {
+    /// Creates a jobserver allowing `jobs` jobs at the same time, including this process
+    pub fn new(jobs: usize) -> io::Result<Self> {
+        Self::from_client(Client::new(jobs.saturating_sub(1))?)
+    }
+
+    /// Connects to the jobserver of the parent `make` or `birb` from `MAKEFLAGS`
+    ///
+    /// Only the first call looks at the environment, later calls return the same jobserver.
+    ///
+    /// # Safety
+    ///
+    /// Takes ownership of the file descriptors named in the environment, it must
+    /// be called before any file is opened (see [`Client::from_env`]).
+    pub unsafe fn inherit() -> Option<Self> {
+        INHERITED
+            .get_or_init(|| {
+                // SAFETY: upheld by the caller
+                let client = unsafe { Client::from_env() }?;
+                Self::from_client(client)
+                    .inspect_err(|e| log::warn!("Failed to use the jobserver of the parent process: {e}"))
+                    .ok()
+            })
+            .clone()
+    }
+
+    /// The jobserver of the parent process, if [`Jobserver::inherit`] found one
+    pub fn inherited() -> Option<Self> {
+        INHERITED.get().cloned().flatten()
+    }
+
+    fn from_client(client: Client) -> io::Result<Self> {
+        let tokens = Arc::new(Tokens::default());
+        let helper = client.clone().into_helper_thread({
+            let tokens = tokens.clone();
+            move |token| {
+                let mut state = tokens.state.lock().unwrap();
+                state.requested = state.requested.saturating_sub(1);
+                if state.surplus > 0 {
+                    state.surplus -= 1;
+                    // dropping the token returns it to the jobserver
+                    return;
+                }
+                match token {
+                    Ok(token) => state.ready.push(token),
+                    Err(e) => state.error = Some(e),
+                }
+                // kept if nobody waits yet, the next call to `changed` returns at once
+                tokens.changed.notify_one();
+            }
+        })?;
+        Ok(Self(Arc::new(Inner { client, helper, tokens })))
+    }
+
+    /// Takes a token if one is available, without waiting
+    ///
+    /// Returns `None` if there is none yet, a token is then requested and
+    /// [`Jobserver::changed`] returns when it arrives.
+    pub fn try_acquire(&self) -> io::Result<Option<JobToken>> {
+        let mut state = self.0.tokens.state.lock().unwrap();
+        if state.running == 0 {
+            state.running = 1;
+            return Ok(Some(JobToken(self.clone())));
+        }
+        if let Some(token) = state.ready.pop() {
+            state.running += 1;
+            state.held.push(token);
+            return Ok(Some(JobToken(self.clone())));
+        }
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+        // one request at a time, tokens are only taken when a task can start
+        if state.requested == state.surplus {
+            state.requested += 1;
+            self.0.helper.request_token();
+        }
+        Ok(None)
+    }
+
+    /// Waits until a requested token arrives or a token is released, or for a short while
+    pub async fn changed(&self) {
+        let _ = tokio::time::timeout(POLL_INTERVAL, self.0.tokens.changed.notified()).await;
+    }
+
+    /// Returns the tokens acquired but not given to a task, and those still requested
+    pub fn release_unused(&self) {
+        let mut state = self.0.tokens.state.lock().unwrap();
+        // dropping the tokens returns them to the jobserver
+        state.ready.clear();
+        state.surplus = state.requested;
+    }
+
+    /// Runs `f`, the steps it starts share the jobserver
+    pub async fn scope<F: Future>(&self, f: F) -> F::Output {
+        CURRENT.scope(self.clone(), f).await
+    }
+}
+
+impl Drop for JobToken {
+    fn drop(&mut self) {
+        let tokens = &self.0.0.tokens;
+        let mut state = tokens.state.lock().unwrap();
+        state.running -= 1;
+        // like make, keep the implicit token and return the others to the jobserver
+        state.held.pop();
+        tokens.changed.notify_one();
+    }
+}
+
+/// Gives the process access to the jobserver of the current task, if any
+///
+/// Sets `MAKEFLAGS`, `MFLAGS` and `CARGO_MAKEFLAGS` and lets the process inherit the jobserver pipe.
+pub fn configure(command: &mut std::process::Command) {
+    let _ = CURRENT.try_with(|jobserver| jobserver.0.client.configure_make(command));
+}
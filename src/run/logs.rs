@@ -0,0 +1,193 @@
+use std::{cell::RefCell, fs::{self, File}, future::Future, io::{self, BufWriter, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::SystemTime};
+
+use sha2::{Digest, Sha256};
+
+use crate::{run::{execution::{OutputLine, OutputStream}, report::{timestamp, TaskReport}}, task::ResolvedTaskInvocation};
+
+/// Number of runs whose logs are kept, the oldest ones are removed when a run writes its first log
+const KEPT_RUNS: usize = 20;
+
+/// Logs of a run, with the full output of every task invocation that ran
+///
+/// Each run writes to its own directory, `.birb/logs/<run-id>/`, in which every
+/// invocation gets a `<task>-<args-hash>.log` file. Each line of a log starts
+/// with a timestamp and what it is: a step command, a line written by the step
+/// to `stdout` or `stderr`, or the status of the task.
+///
+/// The directory is only created with the first log, so runs where nothing ran
+/// don't push the logs of the previous runs out.
+#[derive(Debug)]
+pub struct RunLogs {
+    logs_dir: PathBuf,
+    dir: PathBuf,
+    /// Whether the directory of the run was created
+    created: Mutex<bool>,
+}
+
+/// The open log of a task, created with its first line
+struct TaskLog {
+    logs: Arc<RunLogs>,
+    path: PathBuf,
+    file: Option<BufWriter<File>>,
+}
+
+tokio::task_local! {
+    /// The log of the current task
+    static CURRENT: RefCell<Option<TaskLog>>;
+}
+
+impl RunLogs {
+    /// The logs of a new run in `state_dir`
+    pub fn new(state_dir: impl AsRef<Path>) -> Self {
+        let logs_dir = logs_dir(state_dir);
+        // sortable, and unique even if nested runs start at the same time
+        let run_id = format!("{}-{}", chrono::Utc::now().format("%Y%m%dT%H%M%S%.3f"), std::process::id());
+        let dir = logs_dir.join(run_id);
+        Self {
+            logs_dir,
+            dir,
+            created: Mutex::new(false),
+        }
+    }
+
+    /// Creates the directory of the run, if not done yet, and removes the oldest runs
+    fn create_dir(&self) -> io::Result<()> {
+        let mut created = self.created.lock().unwrap();
+        if *created {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        *created = true;
+
+        // runs that didn't write any log yet don't count, they might still be starting
+        let runs = runs(&self.logs_dir)?
+            .into_iter()
+            .filter(|run| fs::read_dir(run).is_ok_and(|mut entries| entries.next().is_some()))
+            .collect::<Vec<_>>();
+        for old in runs.iter().take(runs.len().saturating_sub(KEPT_RUNS)) {
+            if let Err(e) = fs::remove_dir_all(old) {
+                log::warn!("Failed to remove the old logs {}: {e}", old.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs `f`, the steps it runs write their output to the log of `invocation`
+    pub async fn with_task_log<F: Future>(self: &Arc<Self>, invocation: &ResolvedTaskInvocation, f: F) -> F::Output {
+        let log = TaskLog {
+            logs: self.clone(),
+            path: self.dir.join(file_name(invocation)),
+            file: None,
+        };
+        CURRENT.scope(RefCell::new(Some(log)), async {
+            let r = f.await;
+            let log = CURRENT.with(|current| current.take());
+            if let Some(TaskLog { path, file: Some(mut file), .. }) = log {
+                file.flush().unwrap_or_else(|e| log::warn!("Failed to write the log {}: {e}", path.display()));
+            }
+            r
+        }).await
+    }
+}
+
+/// Writes the command of a step to the log of the current task
+pub fn step_started(step: usize, command: &str) {
+    for line in command.lines() {
+        write(&format!("step {}", step + 1), line.as_bytes());
+    }
+}
+
+/// Writes a line of output to the log of the current task
+pub fn output(line: OutputLine) {
+    let kind = match line.stream {
+        OutputStream::Stdout => "stdout",
+        OutputStream::Stderr => "stderr",
+    };
+    write(kind, line.bytes);
+}
+
+/// Writes the status of the current task to its log, if it ran any step
+pub fn task_finished(report: &TaskReport) {
+    let opened = CURRENT
+        .try_with(|current| current.borrow().as_ref().is_some_and(|log| log.file.is_some()))
+        .unwrap_or(false);
+    if opened {
+        let status = match &report.error {
+            Some(error) => format!("{}: {error}", report.status),
+            None => report.status.to_string(),
+        };
+        write("status", status.as_bytes());
+    }
+}
+
+fn write(kind: &str, bytes: &[u8]) {
+    // outside of a task, e.g. while cleaning, nothing is logged
+    let _ = CURRENT.try_with(|current| {
+        let mut current = current.borrow_mut();
+        let Some(log) = current.as_mut() else {
+            return;
+        };
+        let file = match &mut log.file {
+            Some(file) => file,
+            None => match log.logs.create_dir().and_then(|()| File::create(&log.path)) {
+                Ok(file) => log.file.insert(BufWriter::new(file)),
+                Err(e) => {
+                    log::warn!("Failed to create the log {}: {e}", log.path.display());
+                    // don't try again for every line
+                    *current = None;
+                    return;
+                },
+            },
+        };
+        let result = write!(file, "{} {kind} | ", timestamp(SystemTime::now()))
+            .and_then(|()| file.write_all(bytes))
+            .and_then(|()| file.write_all(b"\n"));
+        if let Err(e) = result {
+            log::warn!("Failed to write the log {}: {e}", log.path.display());
+            *current = None;
+        }
+    });
+}
+
+/// Name of the log file of `invocation`, `<task>-<args-hash>.log`
+///
+/// The hash also covers the taskfile, so tasks with the same name in different
+/// taskfiles don't share a log.
+pub fn file_name(invocation: &ResolvedTaskInvocation) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(invocation.r#ref.display_absolute().to_string());
+    hasher.update([0]);
+    hasher.update(serde_json::to_string(&invocation.args).expect("Failed to serialize arguments"));
+    let hash = hasher.finalize().iter().take(8).map(|b| format!("{b:02x}")).collect::<String>();
+
+    let name = invocation.r#ref.name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+        .collect::<String>();
+    format!("{name}-{hash}.log")
+}
+
+/// Finds the log of `invocation` in the most recent run where it ran
+pub fn find_last(state_dir: impl AsRef<Path>, invocation: &ResolvedTaskInvocation) -> io::Result<Option<PathBuf>> {
+    let logs_dir = logs_dir(state_dir);
+    if !logs_dir.exists() {
+        return Ok(None);
+    }
+    let name = file_name(invocation);
+    Ok(runs(&logs_dir)?.into_iter().rev().map(|run| run.join(&name)).find(|path| path.is_file()))
+}
+
+fn logs_dir(state_dir: impl AsRef<Path>) -> PathBuf {
+    state_dir.as_ref().join("logs")
+}
+
+/// Directories of the runs, oldest first
+fn runs(logs_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut runs = fs::read_dir(logs_dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .filter(|path| path.as_ref().map_or(true, |path| path.is_dir()))
+        .collect::<Result<Vec<_>, _>>()?;
+    runs.sort();
+    Ok(runs)
+}
@@ -0,0 +1,182 @@
+use std::{collections::BTreeMap, fmt::Display, path::Path, time::{Duration, SystemTime}};
+
+use serde::Serialize;
+use serde_json::Value as Json;
+
+use crate::{run::{execution::{triggers::TriggerDecision, TaskExecutionError}, RunError}, task::ResolvedTaskInvocation};
+
+/// What happened to every task of a run, in execution order
+#[derive(Debug, Default)]
+pub struct RunReport {
+    pub tasks: Vec<TaskReport>,
+    /// The run was stopped by an interrupt
+    pub interrupted: bool,
+}
+
+#[derive(Debug)]
+pub struct TaskReport {
+    pub invocation: ResolvedTaskInvocation,
+    pub status: TaskStatus,
+    /// Why the task ran or not, `None` if it was never checked
+    pub decision: Option<TriggerDecision>,
+    pub start: Option<SystemTime>,
+    pub end: Option<SystemTime>,
+    /// Exit code of the failed step, if it exited normally
+    pub exit_code: Option<i32>,
+    /// Why the task failed
+    pub error: Option<RunError>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TaskStatus {
+    Ran,
+    UpToDate,
+    Failed,
+    /// The task was not run, because a dependency failed or the run stopped early
+    Skipped,
+    /// The task was stopped while running
+    Interrupted,
+}
+
+impl RunReport {
+    /// Returns `true` if no task failed and the run was not interrupted
+    pub fn is_success(&self) -> bool {
+        !self.interrupted && self.tasks.iter().all(|task| task.status.is_success())
+    }
+
+    pub fn with_status(&self, status: TaskStatus) -> impl Iterator<Item = &TaskReport> {
+        self.tasks.iter().filter(move |task| task.status == status)
+    }
+
+    /// Turns an unsuccessful run into the corresponding error
+    pub fn into_result(self) -> Result<(), RunError> {
+        if self.interrupted || self.with_status(TaskStatus::Interrupted).next().is_some() {
+            let aborted = self.with_status(TaskStatus::Interrupted).map(|task| task.invocation.clone()).collect();
+            return Err(RunError::Interrupted { aborted });
+        }
+
+        let mut failures = Vec::new();
+        let mut skipped = Vec::new();
+        for task in self.tasks {
+            match task.status {
+                TaskStatus::Failed => failures.push(task.error.expect("failed task without error")),
+                TaskStatus::Skipped => skipped.push(task.invocation),
+                _ => {}
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(RunError::Failed { failures, skipped })
+        }
+    }
+}
+
+impl TaskReport {
+    /// A report for a task that was not run (yet)
+    pub fn new(invocation: &ResolvedTaskInvocation) -> Self {
+        Self {
+            invocation: invocation.clone(),
+            status: TaskStatus::Skipped,
+            decision: None,
+            start: None,
+            end: None,
+            exit_code: None,
+            error: None,
+        }
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        self.end?.duration_since(self.start?).ok()
+    }
+
+    /// Sets the status of the task from the result of its run
+    pub(crate) fn finish(&mut self, result: Result<(), RunError>) {
+        self.end.get_or_insert_with(SystemTime::now);
+        self.status = match result {
+            Ok(()) if self.decision.as_ref().is_some_and(TriggerDecision::should_run) => TaskStatus::Ran,
+            Ok(()) => TaskStatus::UpToDate,
+            Err(RunError::ExecutionError(TaskExecutionError::Interrupted(_))) => TaskStatus::Interrupted,
+            Err(e) => {
+                if let RunError::ExecutionError(TaskExecutionError::StepFailed { failure, .. }) = &e {
+                    self.exit_code = failure.status.code();
+                }
+                self.error = Some(e);
+                TaskStatus::Failed
+            },
+        };
+    }
+}
+
+impl TaskStatus {
+    pub fn is_success(self) -> bool {
+        matches!(self, TaskStatus::Ran | TaskStatus::UpToDate)
+    }
+}
+
+impl Display for TaskStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskStatus::Ran => write!(f, "ran"),
+            TaskStatus::UpToDate => write!(f, "up-to-date"),
+            TaskStatus::Failed => write!(f, "failed"),
+            TaskStatus::Skipped => write!(f, "skipped"),
+            TaskStatus::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonRunReport {
+    pub success: bool,
+    pub interrupted: bool,
+    pub tasks: Vec<JsonTaskReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonTaskReport {
+    pub taskfile: String,
+    pub task: String,
+    pub args: BTreeMap<String, Json>,
+    pub status: TaskStatus,
+    /// RFC 3339 timestamp
+    pub start: Option<String>,
+    /// RFC 3339 timestamp
+    pub end: Option<String>,
+    /// Duration in seconds
+    pub duration: Option<f64>,
+    pub exit_code: Option<i32>,
+    pub reason: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Builds a machine-readable representation of the report, paths are relative to `cwd`
+pub fn to_json(report: &RunReport, cwd: &Path) -> JsonRunReport {
+    JsonRunReport {
+        success: report.is_success(),
+        interrupted: report.interrupted,
+        tasks: report
+            .tasks
+            .iter()
+            .map(|task| JsonTaskReport {
+                taskfile: task.invocation.r#ref.taskfile.to_string(),
+                task: task.invocation.r#ref.name.clone(),
+                args: task.invocation.args.clone(),
+                status: task.status,
+                start: task.start.map(timestamp),
+                end: task.end.map(timestamp),
+                duration: task.duration().map(|d| d.as_secs_f64()),
+                exit_code: task.exit_code,
+                reason: task.decision.as_ref().map(|decision| decision.display_relative(cwd).to_string()),
+                error: task.error.as_ref().map(|e| e.to_string()),
+            })
+            .collect(),
+    }
+}
+
+/// Formats a timestamp as RFC 3339, as used in the machine-readable outputs
+pub fn timestamp(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339()
+}
@@ -1,7 +1,9 @@
-use crate::{run::execution::CommandExecutor, task::ResolvedTaskInvocation};
+use crate::{run::{execution::CommandExecutor, report::{RunReport, TaskReport}}, task::{InstantiatedTask, ResolvedTaskInvocation}};
 
 
+pub mod ci;
 pub mod default;
+pub mod json;
 pub mod parallel;
 
 pub trait RunManager: Send + Sync {
@@ -11,11 +13,15 @@ pub trait RunManager: Send + Sync {
 
 pub trait RunExecution: Send + Sync {
     type TaskExecutionContext<'a>: TaskExecutionContext where Self: 'a;
-    fn enter_task<'a>(&'a self, invocation: &'a ResolvedTaskInvocation) -> anyhow::Result<Self::TaskExecutionContext<'a>>;
+    fn enter_task<'a>(&'a self, invocation: &'a ResolvedTaskInvocation, task: &'a InstantiatedTask) -> anyhow::Result<Self::TaskExecutionContext<'a>>;
+    /// Called once all the tasks are done, or the run stopped
+    fn finished(&self, _report: &RunReport) {}
 }
 
 pub trait TaskExecutionContext: Send + Sync {
     fn run(&mut self) -> impl CommandExecutor;
     fn up_to_date(&mut self);
+    /// Called when the task is done, whatever its status
+    fn finished(&mut self, _report: &TaskReport) {}
     // TODO clean, maybe?
 }
\ No newline at end of file
@@ -0,0 +1,145 @@
+use std::{borrow::Borrow, error::Error, path::PathBuf, sync::Mutex, time::Duration};
+
+use anyhow::anyhow;
+
+use crate::{cli::CliRunOptions, run::{display_args, execution::{naive::NaiveExecutor, CommandExecutor, OutputStream, StepFailure, TaskExecutionError}, report::{TaskReport, TaskStatus}, run_manager::{RunExecution, RunManager, TaskExecutionContext}}, task::{InstantiatedTask, OutputMode, ResolvedTaskInvocation}};
+
+/// Reports the run as plain logs for CI, without progress bars
+///
+/// The output of every task that runs is wrapped in a GitHub Actions group
+/// (`::group::` / `::endgroup::`) and failed tasks get an `::error` annotation.
+/// In parallel runs, the output of a task is printed once it finishes so that
+/// the groups don't overlap.
+pub struct CiRunManager<C: Borrow<CliRunOptions> + Send + Sync> {
+    pub options: C,
+    /// Several tasks may run at the same time
+    pub parallel: bool,
+}
+
+impl<C: Borrow<CliRunOptions> + Send + Sync + Clone> RunManager for CiRunManager<C> {
+    type RunExecution = CiRunExecution<C>;
+    fn begin<'a>(self, _invocations: impl IntoIterator<Item = &'a ResolvedTaskInvocation>) -> anyhow::Result<Self::RunExecution> {
+        Ok(CiRunExecution {
+            cwd: std::env::current_dir().map_err(|e| anyhow!("Failed to get current directory: {e}"))?,
+            options: self.options,
+            parallel: self.parallel,
+            print: Mutex::new(()),
+        })
+    }
+}
+
+pub struct CiRunExecution<C: Borrow<CliRunOptions> + Send + Sync> {
+    cwd: PathBuf,
+    options: C,
+    parallel: bool,
+    /// Held while printing a group, so that groups of parallel tasks don't mix
+    print: Mutex<()>,
+}
+
+impl<C: Borrow<CliRunOptions> + Send + Sync + Clone> RunExecution for CiRunExecution<C> {
+    type TaskExecutionContext<'a> = CiTaskExecutionContext<'a, C> where Self: 'a;
+    fn enter_task<'a>(&'a self, invocation: &'a ResolvedTaskInvocation, task: &'a InstantiatedTask) -> anyhow::Result<Self::TaskExecutionContext<'a>> {
+        let args = display_args(invocation);
+        let output = self.options.borrow().output.or(task.body.output).unwrap_or_default();
+        Ok(CiTaskExecutionContext {
+            execution: self,
+            title: format!("{}{}{args}", invocation.r#ref.display_relative(&self.cwd), if args.is_empty() { "" } else { " " }),
+            interactive: task.body.interactive,
+            tty: self.options.borrow().tty || task.body.tty,
+            // interactive tasks run alone, their output is not captured anyway
+            buffered: !task.body.interactive && (self.parallel || output.is_buffered()),
+            output,
+            lines: Vec::new(),
+            started: false,
+        })
+    }
+}
+
+pub struct CiTaskExecutionContext<'a, C: Borrow<CliRunOptions> + Send + Sync> {
+    execution: &'a CiRunExecution<C>,
+    /// The task and its arguments, as shown in the group and the annotation
+    title: String,
+    interactive: bool,
+    tty: bool,
+    output: OutputMode,
+    /// The output is printed once the task finishes, instead of as it is written
+    buffered: bool,
+    lines: Vec<(OutputStream, String)>,
+    /// The task ran, and has a group
+    started: bool,
+}
+
+impl<C: Borrow<CliRunOptions> + Send + Sync> TaskExecutionContext for CiTaskExecutionContext<'_, C> {
+    fn run(&mut self) -> impl CommandExecutor {
+        self.started = true;
+        if !self.buffered {
+            println!("::group::{}", self.title);
+        }
+        NaiveExecutor {
+            output_handler: |line| {
+                if self.buffered {
+                    self.lines.push((line.stream, line.text().into_owned()));
+                } else {
+                    line.stream.println(line.text());
+                }
+            },
+            grace_period: Duration::from_secs(self.execution.options.borrow().grace_period),
+            interactive: self.interactive,
+            tty: self.tty,
+        }
+    }
+
+    fn up_to_date(&mut self) {
+        if !self.execution.options.borrow().compact {
+            println!("{} up-to-date", self.title);
+        }
+    }
+
+    fn finished(&mut self, report: &TaskReport) {
+        let _print = self.execution.print.lock().unwrap();
+        if self.started {
+            if !self.buffered {
+                println!("::endgroup::");
+            } else if !(self.output == OutputMode::QuietOnSuccess && report.status.is_success()) {
+                println!("::group::{}", self.title);
+                for (stream, line) in self.lines.drain(..) {
+                    stream.println(line);
+                }
+                println!("::endgroup::");
+            }
+        }
+        // outside of the group, so that it is seen even when the group is collapsed
+        if report.status == TaskStatus::Failed {
+            let message = match report.error.as_ref().and_then(|e| step_failure(e)) {
+                Some(failure) => {
+                    let mut message = failure.to_string();
+                    for line in &failure.output_tail {
+                        message.push('\n');
+                        message.push_str(line);
+                    }
+                    message
+                },
+                None => report.error.as_ref().map_or_else(|| "failed".to_string(), |e| e.to_string()),
+            };
+            println!("::error title={}::{}", escape_property(&format!("Task {} failed", self.title)), escape_data(&message));
+        }
+    }
+}
+
+/// The failed step behind `error`, if a step failed
+fn step_failure<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a StepFailure> {
+    std::iter::successors(Some(error), |&e| e.source()).find_map(|e| match e.downcast_ref::<TaskExecutionError>() {
+        Some(TaskExecutionError::StepFailed { failure, .. }) => Some(failure),
+        _ => None,
+    })
+}
+
+/// Escapes the message of a workflow command, which must fit on one line
+fn escape_data(s: &str) -> String {
+    s.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+/// Escapes a property of a workflow command, like `title`
+fn escape_property(s: &str) -> String {
+    escape_data(s).replace(':', "%3A").replace(',', "%2C")
+}
@@ -1,21 +1,24 @@
-use std::{borrow::Borrow, path::PathBuf};
+use std::{borrow::Borrow, path::PathBuf, time::Duration};
 
 use anyhow::anyhow;
 use colored::Colorize;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 
-use crate::{cli::CliRunOptions, run::{display_args, execution::{naive::NaiveExecutor, CommandExecutor}, run_manager::{RunExecution, RunManager, TaskExecutionContext}}, task::ResolvedTaskInvocation};
+use crate::{cli::CliRunOptions, run::{display_args, execution::{naive::NaiveExecutor, CommandExecutor, OutputStream}, report::TaskReport, run_manager::{RunExecution, RunManager, TaskExecutionContext}}, task::{InstantiatedTask, OutputMode, ResolvedTaskInvocation}};
 
 pub struct DefaultRunManager<C: Borrow<CliRunOptions> + Send + Sync>(pub C); // TODO also use options while cleaning
 
 impl<C: Borrow<CliRunOptions> + Send + Sync + Clone> RunManager for DefaultRunManager<C> {
     type RunExecution = DefaultRunExecution<C>;
     fn begin<'a>(self, invocations: impl IntoIterator<Item = &'a ResolvedTaskInvocation>) -> anyhow::Result<Self::RunExecution> {
-        let bar = ProgressBar::new(invocations.into_iter().count() as u64);
+        // in a MultiProgress only to be able to clear it, see `TaskBody::interactive`
+        let m = MultiProgress::new();
+        let bar = m.add(ProgressBar::new(invocations.into_iter().count() as u64));
         bar.set_style(ProgressStyle::with_template("[{elapsed_precise}] [{bar:40.green/white}] {pos:>7}/{len:7} {msg}")?
             .progress_chars("=>-"));
         Ok(DefaultRunExecution {
             bar,
+            m,
             options: self.0,
         })
     }
@@ -23,6 +26,7 @@ impl<C: Borrow<CliRunOptions> + Send + Sync + Clone> RunManager for DefaultRunMa
 
 pub struct DefaultRunExecution<C: Borrow<CliRunOptions> + Send + Sync> {
     bar: ProgressBar,
+    m: MultiProgress,
     options: C,
 }
 
@@ -34,14 +38,19 @@ impl<C: Borrow<CliRunOptions> + Send + Sync> Drop for DefaultRunExecution<C> {
 
 impl<C: Borrow<CliRunOptions> + Send + Sync + Clone> RunExecution for DefaultRunExecution<C> {
     type TaskExecutionContext<'a> = DefaultTaskExecutionContext<'a, C> where Self: 'a;
-    fn enter_task<'a>(&'a self, invocation: &'a ResolvedTaskInvocation) -> anyhow::Result<Self::TaskExecutionContext<'a>> {
+    fn enter_task<'a>(&'a self, invocation: &'a ResolvedTaskInvocation, task: &'a InstantiatedTask) -> anyhow::Result<Self::TaskExecutionContext<'a>> {
         self.bar.inc(1);
         let args = display_args(invocation);
         self.bar.set_message(format!("task: {} {args}", invocation.r#ref.display_relative(&std::env::current_dir().unwrap()).to_string().bold().green()));
         Ok(DefaultTaskExecutionContext {
             bar: &self.bar,
+            m: &self.m,
             invocation,
+            interactive: task.body.interactive,
+            tty: self.options.borrow().tty || task.body.tty,
             cwd: std::env::current_dir().map_err(|e| anyhow!("Failed to get current directory: {e}"))?,
+            output: self.options.borrow().output.or(task.body.output).unwrap_or_default(),
+            buffered: Vec::new(),
             options: self.options.clone(),
         })
     }
@@ -49,8 +58,17 @@ impl<C: Borrow<CliRunOptions> + Send + Sync + Clone> RunExecution for DefaultRun
 
 pub struct DefaultTaskExecutionContext<'a, C: Borrow<CliRunOptions> + Send + Sync> {
     bar: &'a ProgressBar,
+    m: &'a MultiProgress,
     invocation: &'a ResolvedTaskInvocation,
+    /// The progress bar is hidden while the task uses the terminal
+    interactive: bool,
+    /// The steps write to pseudo-terminals, see `CliRunOptions::tty`
+    tty: bool,
     cwd: PathBuf,
+    /// Only one task runs at a time, so only `quiet-on-success` changes how the output is shown
+    output: OutputMode,
+    /// Output kept until the task finishes
+    buffered: Vec<(OutputStream, String)>,
     options: C,
 }
 
@@ -62,20 +80,31 @@ impl<C: Borrow<CliRunOptions> + Send + Sync> TaskExecutionContext for DefaultTas
                 println!("    {} {args}\trunning...", self.invocation.r#ref.display_relative(&self.cwd).to_string().bold().green());
             });
         }
+        if self.interactive {
+            let _ = self.m.clear();
+            self.m.set_draw_target(ProgressDrawTarget::hidden());
+        }
         NaiveExecutor {
             output_handler: |output| {
+                if self.output == OutputMode::QuietOnSuccess {
+                    self.buffered.push((output.stream, output.text().into_owned()));
+                    return;
+                }
                 // ! self.bar.suspend(|| println!("{output}"));
 self.bar.suspend(|| {
     //let mut s = stderr();
     //s.queue(cursor::MoveUp(1)).unwrap();
     //s.queue(terminal::Clear(terminal::ClearType::CurrentLine)).unwrap();
     //s.flush().unwrap();
-    println!("{output}");
+    output.stream.println(output.text());
     //s.queue(cursor::MoveToColumn(0)).unwrap();
     //writeln!(&mut s, " === OK ===").unwrap();
     //s.flush().unwrap();
 });
             },
+            grace_period: Duration::from_secs(self.options.borrow().grace_period),
+            interactive: self.interactive,
+            tty: self.tty,
         }
     }
 
@@ -87,4 +116,17 @@ self.bar.suspend(|| {
             });
         }
     }
-}
\ No newline at end of file
+
+    fn finished(&mut self, report: &TaskReport) {
+        if self.interactive {
+            self.m.set_draw_target(ProgressDrawTarget::stderr());
+        }
+        if !report.status.is_success() {
+            self.bar.suspend(|| {
+                for (stream, line) in self.buffered.drain(..) {
+                    stream.println(line);
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::{borrow::Borrow, collections::BTreeMap, fs::File, io::{LineWriter, Write}, path::PathBuf, sync::Mutex, time::{Duration, SystemTime}};
+
+use anyhow::anyhow;
+use serde::Serialize;
+use serde_json::Value as Json;
+
+use crate::{cli::CliRunOptions, run::{execution::{naive::NaiveExecutor, CommandExecutor, OutputStream}, report::{timestamp, RunReport, TaskReport, TaskStatus}, run_manager::{RunExecution, RunManager, TaskExecutionContext}}, task::{InstantiatedTask, ResolvedTaskInvocation}};
+
+/// Reports the run as newline-delimited JSON events, for tools following it live
+///
+/// The events are written to the file given with `--message-file`, or to stdout.
+pub struct JsonRunManager<C: Borrow<CliRunOptions> + Send + Sync>(pub C);
+
+impl<C: Borrow<CliRunOptions> + Send + Sync + Clone> RunManager for JsonRunManager<C> {
+    type RunExecution = JsonRunExecution<C>;
+    fn begin<'a>(self, invocations: impl IntoIterator<Item = &'a ResolvedTaskInvocation>) -> anyhow::Result<Self::RunExecution> {
+        let out: Box<dyn Write + Send> = match &self.0.borrow().message_file {
+            Some(path) => Box::new(LineWriter::new(
+                File::create(path).map_err(|e| anyhow!("Failed to create {}: {e}", path.display()))?,
+            )),
+            None => Box::new(std::io::stdout()),
+        };
+        let execution = JsonRunExecution {
+            out: Mutex::new(out),
+            cwd: std::env::current_dir().map_err(|e| anyhow!("Failed to get current directory: {e}"))?,
+            options: self.0,
+            start: SystemTime::now(),
+        };
+        execution.emit(Event::Plan {
+            tasks: invocations.into_iter().map(JsonInvocation::from).collect(),
+        });
+        Ok(execution)
+    }
+}
+
+pub struct JsonRunExecution<C: Borrow<CliRunOptions> + Send + Sync> {
+    out: Mutex<Box<dyn Write + Send>>,
+    cwd: PathBuf,
+    options: C,
+    start: SystemTime,
+}
+
+impl<C: Borrow<CliRunOptions> + Send + Sync> JsonRunExecution<C> {
+    fn emit(&self, event: Event) {
+        let line = serde_json::to_string(&TimedEvent { time: timestamp(SystemTime::now()), event })
+            .expect("Failed to serialize event");
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = writeln!(out, "{line}").and_then(|_| out.flush()) {
+            log::error!("Failed to write event: {e}");
+        }
+    }
+}
+
+impl<C: Borrow<CliRunOptions> + Send + Sync + Clone> RunExecution for JsonRunExecution<C> {
+    type TaskExecutionContext<'a> = JsonTaskExecutionContext<'a, C> where Self: 'a;
+    fn enter_task<'a>(&'a self, invocation: &'a ResolvedTaskInvocation, task: &'a InstantiatedTask) -> anyhow::Result<Self::TaskExecutionContext<'a>> {
+        self.emit(Event::TaskStarted { task: invocation.into() });
+        Ok(JsonTaskExecutionContext {
+            execution: self,
+            invocation,
+            interactive: task.body.interactive,
+            tty: self.options.borrow().tty || task.body.tty,
+        })
+    }
+
+    fn finished(&self, report: &RunReport) {
+        self.emit(Event::RunFinished {
+            success: report.is_success(),
+            interrupted: report.interrupted,
+            duration: self.start.elapsed().unwrap_or_default().as_secs_f64(),
+        });
+    }
+}
+
+pub struct JsonTaskExecutionContext<'a, C: Borrow<CliRunOptions> + Send + Sync> {
+    execution: &'a JsonRunExecution<C>,
+    invocation: &'a ResolvedTaskInvocation,
+    interactive: bool,
+    tty: bool,
+}
+
+impl<C: Borrow<CliRunOptions> + Send + Sync> TaskExecutionContext for JsonTaskExecutionContext<'_, C> {
+    fn run(&mut self) -> impl CommandExecutor {
+        NaiveExecutor {
+            output_handler: |line| self.execution.emit(Event::OutputLine {
+                task: self.invocation.into(),
+                stream: line.stream,
+                line: &line.text(),
+            }),
+            grace_period: Duration::from_secs(self.execution.options.borrow().grace_period),
+            interactive: self.interactive,
+            tty: self.tty,
+        }
+    }
+
+    fn up_to_date(&mut self) {
+        self.execution.emit(Event::UpToDate { task: self.invocation.into() });
+    }
+
+    fn finished(&mut self, report: &TaskReport) {
+        self.execution.emit(Event::TaskFinished {
+            task: self.invocation.into(),
+            status: report.status,
+            duration: report.duration().map(|d| d.as_secs_f64()),
+            exit_code: report.exit_code,
+            reason: report.decision.as_ref().map(|decision| decision.display_relative(&self.execution.cwd).to_string()),
+            error: report.error.as_ref().map(|e| e.to_string()),
+        });
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TimedEvent<'a> {
+    /// RFC 3339 timestamp
+    time: String,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum Event<'a> {
+    /// The tasks that may run, in execution order
+    Plan {
+        tasks: Vec<JsonInvocation<'a>>,
+    },
+    TaskStarted {
+        #[serde(flatten)]
+        task: JsonInvocation<'a>,
+    },
+    OutputLine {
+        #[serde(flatten)]
+        task: JsonInvocation<'a>,
+        stream: OutputStream,
+        /// Invalid UTF-8 is replaced with `U+FFFD`
+        line: &'a str,
+    },
+    UpToDate {
+        #[serde(flatten)]
+        task: JsonInvocation<'a>,
+    },
+    TaskFinished {
+        #[serde(flatten)]
+        task: JsonInvocation<'a>,
+        status: TaskStatus,
+        /// Duration in seconds
+        duration: Option<f64>,
+        exit_code: Option<i32>,
+        reason: Option<String>,
+        error: Option<String>,
+    },
+    RunFinished {
+        success: bool,
+        interrupted: bool,
+        /// Duration in seconds
+        duration: f64,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct JsonInvocation<'a> {
+    taskfile: String,
+    task: &'a str,
+    args: &'a BTreeMap<String, Json>,
+}
+
+impl<'a> From<&'a ResolvedTaskInvocation> for JsonInvocation<'a> {
+    fn from(invocation: &'a ResolvedTaskInvocation) -> Self {
+        Self {
+            taskfile: invocation.r#ref.taskfile.to_string(),
+            task: &invocation.r#ref.name,
+            args: &invocation.args,
+        }
+    }
+}
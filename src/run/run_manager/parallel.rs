@@ -1,10 +1,10 @@
-use std::{borrow::Borrow, path::PathBuf, sync::Mutex};
+use std::{borrow::Borrow, path::PathBuf, sync::Mutex, time::Duration};
 
 use anyhow::anyhow;
 use colored::Colorize;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 
-use crate::{cli::CliRunOptions, run::{display_args, execution::{naive::NaiveExecutor, CommandExecutor}, run_manager::{RunExecution, RunManager, TaskExecutionContext}}, task::ResolvedTaskInvocation};
+use crate::{cli::CliRunOptions, run::{display_args, execution::{naive::NaiveExecutor, CommandExecutor, OutputStream}, report::TaskReport, run_manager::{RunExecution, RunManager, TaskExecutionContext}}, task::{InstantiatedTask, OutputMode, ResolvedTaskInvocation}};
 
 pub struct ParallelRunManager<C: Borrow<CliRunOptions> + Send + Sync>(pub C); // TODO also use options while cleaning
 
@@ -54,7 +54,7 @@ impl<C: Borrow<CliRunOptions> + Send + Sync> Drop for ParallelRunExecution<C> {
 
 impl<C: Borrow<CliRunOptions> + Send + Sync + Clone> RunExecution for ParallelRunExecution<C> {
     type TaskExecutionContext<'a> = ParallelTaskExecutionContext<'a, C> where Self: 'a;
-    fn enter_task<'a>(&'a self, invocation: &'a ResolvedTaskInvocation) -> anyhow::Result<Self::TaskExecutionContext<'a>> {
+    fn enter_task<'a>(&'a self, invocation: &'a ResolvedTaskInvocation, task: &'a InstantiatedTask) -> anyhow::Result<Self::TaskExecutionContext<'a>> {
         self.bar.inc(1);
         let args = display_args(invocation);
         self.bar.set_message(format!("task: {} {args}", invocation.r#ref.display_relative(&std::env::current_dir().unwrap()).to_string().bold().green()));
@@ -75,6 +75,9 @@ impl<C: Borrow<CliRunOptions> + Send + Sync + Clone> RunExecution for ParallelRu
         t.set_message(t_message.clone());
         Ok(ParallelTaskExecutionContext {
             bar: &self.bar,
+            m: &self._m,
+            interactive: task.body.interactive,
+            tty: self.options.borrow().tty || task.body.tty,
             invocation,
             cwd: std::env::current_dir().map_err(|e| anyhow!("Failed to get current directory: {e}"))?,
             options: self.options.clone(),
@@ -82,12 +85,19 @@ impl<C: Borrow<CliRunOptions> + Send + Sync + Clone> RunExecution for ParallelRu
             t_message,
             idx,
             last_was: &self.last_was,
+            output: self.options.borrow().output.or(task.body.output).unwrap_or_default(),
+            buffered: Mutex::new(Vec::new()),
         })
     }
 }
 
 pub struct ParallelTaskExecutionContext<'a, C: Borrow<CliRunOptions> + Send + Sync> {
     bar: &'a ProgressBar,
+    m: &'a MultiProgress,
+    /// The task runs alone and the progress bars are hidden while it uses the terminal
+    interactive: bool,
+    /// The steps write to pseudo-terminals, see `CliRunOptions::tty`
+    tty: bool,
     last_was: &'a Mutex<usize>,
     invocation: &'a ResolvedTaskInvocation,
     cwd: PathBuf,
@@ -95,6 +105,9 @@ pub struct ParallelTaskExecutionContext<'a, C: Borrow<CliRunOptions> + Send + Sy
     t: ProgressBar,
     t_message: String,
     idx: usize,
+    output: OutputMode,
+    /// Output kept until the task finishes, in the buffered modes
+    buffered: Mutex<Vec<(OutputStream, String)>>,
 }
 
 impl<C: Borrow<CliRunOptions> + Send + Sync> Drop for ParallelTaskExecutionContext<'_, C> {
@@ -104,6 +117,32 @@ impl<C: Borrow<CliRunOptions> + Send + Sync> Drop for ParallelTaskExecutionConte
     }
 }
 
+impl<C: Borrow<CliRunOptions> + Send + Sync> ParallelTaskExecutionContext<'_, C> {
+    /// Prints lines of the task without being interleaved with other tasks,
+    /// after the number of the task unless the mode is `interleaved`
+    fn print_output(&self, lines: impl IntoIterator<Item = (OutputStream, String)>) {
+        // ! self.bar.suspend(|| println!("{output}"));
+        self.bar.suspend(|| {
+            let color = COLOR_RING[self.idx % COLOR_RING.len()];
+            let mut last = self.last_was.lock().unwrap();
+            for (stream, line) in lines {
+                if self.output == OutputMode::Interleaved {
+                    *last = usize::MAX;
+                    stream.println(line);
+                    continue;
+                }
+                let prefix = if *last == self.idx {
+                    format!("       | ")
+                } else {
+                    format!("#{:<5} | ", self.idx)
+                }.color(color).dimmed();
+                *last = self.idx;
+                stream.println(format!("{prefix}{line}"));
+            }
+        });
+    }
+}
+
 impl<C: Borrow<CliRunOptions> + Send + Sync> TaskExecutionContext for ParallelTaskExecutionContext<'_, C> {
     fn run(&mut self) -> impl CommandExecutor {
         let args = display_args(self.invocation);
@@ -112,12 +151,17 @@ impl<C: Borrow<CliRunOptions> + Send + Sync> TaskExecutionContext for ParallelTa
                 println!("    {} {args}\trunning... #{}", self.invocation.r#ref.display_relative(&self.cwd).to_string().bold().green(), self.idx);
             });
         }
+        if self.interactive {
+            let _ = self.m.clear();
+            self.m.set_draw_target(ProgressDrawTarget::hidden());
+        }
         // TODO when finished, print a normal line so that the information about the task id is not lost
         NaiveExecutor {
-            output_handler: |output| {
+            output_handler: |line| {
                 self.t.tick();
                 self.bar.tick();
 
+                let output = &*line.text();
                 let mut first_output_part: &str = output;
                 let mut second_output_part: &str = "";
 
@@ -139,19 +183,16 @@ impl<C: Borrow<CliRunOptions> + Send + Sync> TaskExecutionContext for ParallelTa
                     }
                 }
 
-                // ! self.bar.suspend(|| println!("{output}"));
-                self.bar.suspend(|| {
-                    let color = COLOR_RING[self.idx % COLOR_RING.len()];
-                    let mut last = self.last_was.lock().unwrap();
-                    let prefix = if *last == self.idx {
-                        format!("       | ")
-                    } else {
-                        format!("#{:<5} | ", self.idx)
-                    }.color(color).dimmed();
-                    *last = self.idx;
-                    println!("{prefix}{first_output_part}{second_output_part}");
-                });
+                let output = format!("{first_output_part}{second_output_part}");
+                if self.output.is_buffered() {
+                    self.buffered.lock().unwrap().push((line.stream, output));
+                } else {
+                    self.print_output([(line.stream, output)]);
+                }
             },
+            grace_period: Duration::from_secs(self.options.borrow().grace_period),
+            interactive: self.interactive,
+            tty: self.tty,
         }
     }
 
@@ -163,4 +204,19 @@ impl<C: Borrow<CliRunOptions> + Send + Sync> TaskExecutionContext for ParallelTa
             });
         }
     }
-}
\ No newline at end of file
+
+    fn finished(&mut self, report: &TaskReport) {
+        if self.interactive {
+            self.m.set_draw_target(ProgressDrawTarget::stderr());
+        }
+        let buffered = std::mem::take(self.buffered.get_mut().unwrap());
+        let show = match self.output {
+            OutputMode::Grouped => true,
+            OutputMode::QuietOnSuccess => !report.status.is_success(),
+            OutputMode::Interleaved | OutputMode::Prefixed => false,
+        };
+        if show && !buffered.is_empty() {
+            self.print_output(buffered);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::{collections::{BTreeMap, BTreeSet}, fs::File, future::Future, io::BufWriter, path::Path, sync::{Arc, Mutex}, time::Instant};
+
+use serde::Serialize;
+use serde_json::Value as Json;
+
+/// Profile of a run, written in the Chrome `trace_event` format
+///
+/// Tasks are recorded on lanes (threads in the trace viewers), a lane is
+/// only used by one task at a time. The slices recorded with [`span`] while
+/// a task runs are nested in the slice of the task.
+#[derive(Debug)]
+pub struct Trace {
+    start: Instant,
+    events: Mutex<Vec<TraceEvent>>,
+    lanes: Mutex<Lanes>,
+}
+
+#[derive(Debug, Default)]
+struct Lanes {
+    count: usize,
+    free: BTreeSet<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct TraceEvent {
+    name: String,
+    cat: &'static str,
+    ph: &'static str,
+    /// Start time in microseconds
+    ts: f64,
+    /// Duration in microseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<f64>,
+    pid: u32,
+    tid: usize,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    args: BTreeMap<String, Json>,
+}
+
+tokio::task_local! {
+    /// The trace and the lane of the current task
+    static CURRENT: (Arc<Trace>, usize);
+}
+
+impl Trace {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            start: Instant::now(),
+            events: Mutex::new(Vec::new()),
+            lanes: Mutex::new(Lanes::default()),
+        })
+    }
+
+    /// Runs `f` on the first free lane, the spans started by `f` are recorded on that lane
+    pub async fn on_lane<F: Future>(self: &Arc<Self>, f: F) -> F::Output {
+        let lane = {
+            let mut lanes = self.lanes.lock().unwrap();
+            lanes.free.pop_first().unwrap_or_else(|| {
+                lanes.count += 1;
+                lanes.count - 1
+            })
+        };
+
+        let r = CURRENT.scope((self.clone(), lane), f).await;
+
+        self.lanes.lock().unwrap().free.insert(lane);
+        r
+    }
+
+    /// Writes the trace, it can be opened with Perfetto or `chrome://tracing`
+    pub fn write(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let lanes = (0..self.lanes.lock().unwrap().count).map(|lane| TraceEvent {
+            name: "thread_name".to_string(),
+            cat: "__metadata",
+            ph: "M",
+            ts: 0.0,
+            dur: None,
+            pid: 1,
+            tid: lane,
+            args: [("name".to_string(), Json::from(format!("worker {lane}")))].into_iter().collect(),
+        });
+        let events = self.events.lock().unwrap();
+
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct TraceFile<'a> {
+            trace_events: Vec<&'a TraceEvent>,
+            display_time_unit: &'static str,
+        }
+
+        let lanes = lanes.collect::<Vec<_>>();
+        let file = TraceFile {
+            trace_events: lanes.iter().chain(events.iter()).collect(),
+            display_time_unit: "ms",
+        };
+        serde_json::to_writer(BufWriter::new(File::create(path)?), &file)?;
+        Ok(())
+    }
+}
+
+/// Starts a slice on the lane of the current task, recorded when the returned span is dropped
+///
+/// Does nothing outside of a traced task.
+pub fn span(name: impl FnOnce() -> String, cat: &'static str) -> Span {
+    Span(CURRENT.try_with(|(trace, lane)| OpenSpan {
+        trace: trace.clone(),
+        lane: *lane,
+        name: name(),
+        cat,
+        start: Instant::now(),
+        args: BTreeMap::new(),
+    }).ok())
+}
+
+#[must_use = "the slice ends when the span is dropped"]
+pub struct Span(Option<OpenSpan>);
+
+struct OpenSpan {
+    trace: Arc<Trace>,
+    lane: usize,
+    name: String,
+    cat: &'static str,
+    start: Instant,
+    args: BTreeMap<String, Json>,
+}
+
+impl Span {
+    /// Attaches a value to the slice, shown when it is selected in the viewer
+    pub fn arg(&mut self, key: &str, value: impl FnOnce() -> Json) {
+        if let Some(span) = &mut self.0 {
+            span.args.insert(key.to_string(), value());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(span) = self.0.take() else {
+            return;
+        };
+        let micros = |instant: Instant| instant.duration_since(span.trace.start).as_secs_f64() * 1e6;
+        let event = TraceEvent {
+            name: span.name,
+            cat: span.cat,
+            ph: "X",
+            ts: micros(span.start),
+            dur: Some(span.start.elapsed().as_secs_f64() * 1e6),
+            pid: 1,
+            tid: span.lane,
+            args: span.args,
+        };
+        span.trace.events.lock().unwrap().push(event);
+    }
+}
@@ -5,14 +5,19 @@ mod instantiation;
 pub use instantiation::{ArgumentsCheckError, InstantiationError};
 
 mod invocation;
+mod output;
 mod params;
+mod patterns;
+pub mod resources;
 mod task_ref;
 mod task;
 mod taskfile;
 mod workspace;
 
 pub use invocation::*;
+pub use output::OutputMode;
 pub use params::*;
+pub use patterns::PathPatterns;
 use serde::Serialize;
 pub use task_ref::*;
 pub use task::*;
@@ -3,11 +3,14 @@ use std::{collections::HashSet, path::PathBuf, str::FromStr};
 use serde_json::{Number, Value as Json};
 use yaml_rust::Yaml;
 
-use crate::task::Task;
+use crate::task::{OutputMode, Task};
 
 mod command;
 mod deps;
 mod io;
+mod resources;
+
+pub(crate) use resources::{parse_amounts, InvalidResources};
 
 #[derive(Debug)]
 #[derive(thiserror::Error)]
@@ -22,6 +25,18 @@ pub enum InvalidTaskObject {
     InvalidWorkdirType,
     #[error("Invalid phony, expected a boolean")]
     InvalidPhonyType,
+    #[error("Invalid interactive, expected a boolean")]
+    InvalidInteractiveType,
+    #[error("Invalid tty, expected a boolean")]
+    InvalidTtyType,
+    #[error("Invalid weight, expected a non-negative number")]
+    InvalidWeight,
+    #[error("Invalid resources: {0}")]
+    InvalidResources(#[from] resources::InvalidResources),
+    #[error("Invalid locks: {0}")]
+    InvalidLocks(#[from] resources::InvalidLocks),
+    #[error("Invalid output, expected one of interleaved, prefixed, grouped or quiet-on-success")]
+    InvalidOutput,
     #[error("Invalid dependencies: {0}")]
     InvalidDependencies(#[from] deps::DepParsingError),
     #[error("Invalid parameters: {0}")]
@@ -79,6 +94,45 @@ pub fn parse_task(workdir: impl Into<PathBuf>, name: &str, value: &Yaml) -> Resu
         used_keys.insert("phony");
     }
 
+    if let Some(value) = value.get(&Yaml::String("interactive".into())) {
+        task.body.interactive = value
+            .as_bool()
+            .ok_or(InvalidTaskObject::InvalidInteractiveType)?;
+        used_keys.insert("interactive");
+    }
+
+    if let Some(value) = value.get(&Yaml::String("tty".into())) {
+        task.body.tty = value
+            .as_bool()
+            .ok_or(InvalidTaskObject::InvalidTtyType)?;
+        used_keys.insert("tty");
+    }
+
+    if let Some(value) = value.get(&Yaml::String("weight".into())) {
+        let weight = match value {
+            Yaml::Real(_) => value.as_f64(),
+            Yaml::Integer(i) => Some(*i as f64),
+            _ => None,
+        };
+        task.body.weight = Some(weight.filter(|w| *w >= 0.0).ok_or(InvalidTaskObject::InvalidWeight)?);
+        used_keys.insert("weight");
+    }
+
+    if let Some(value) = value.get(&Yaml::String("resources".into())) {
+        resources::parse_resources(&mut task, value)?;
+        used_keys.insert("resources");
+    }
+
+    if let Some(value) = value.get(&Yaml::String("locks".into())) {
+        resources::parse_locks(&mut task, value)?;
+        used_keys.insert("locks");
+    }
+
+    if let Some(value) = value.get(&Yaml::String("output".into())) {
+        task.body.output = Some(parse_output_mode(value).ok_or(InvalidTaskObject::InvalidOutput)?);
+        used_keys.insert("output");
+    }
+
     if let Some(deps) = value.get(&Yaml::String("deps".into())) {
         deps::parse_deps(&mut task, deps)?;
         used_keys.insert("deps");
@@ -130,6 +184,11 @@ pub fn parse_task(workdir: impl Into<PathBuf>, name: &str, value: &Yaml) -> Resu
     Ok(task)
 }
 
+/// Parses an output mode, as used by task and taskfile `output`
+pub(crate) fn parse_output_mode(value: &Yaml) -> Option<OutputMode> {
+    value.as_str().and_then(OutputMode::from_name)
+}
+
 #[derive(Debug)]
 #[derive(thiserror::Error)]
 pub enum YamlToJsonError {
@@ -2,7 +2,7 @@ use linked_hash_map::LinkedHashMap;
 use yaml_rust::Yaml;
 use serde_json::Value as Json;
 
-use crate::task::{from_yaml::{yaml_to_json, YamlToJsonError}, ArgType, OutputPath, Param, Task};
+use crate::task::{from_yaml::{yaml_to_json, YamlToJsonError}, ArgType, OutputPath, Param, PathPatterns, Task};
 
 #[derive(Debug)]
 #[derive(thiserror::Error)]
@@ -39,6 +39,8 @@ pub enum InvalidSources {
     NotAnArray,
     #[error("Invalid source at index {0}, expected a string but got: {1:?}")]
     NotAString(usize, Yaml),
+    #[error("Invalid source pattern at index {0}: {1}")]
+    InvalidPattern(usize, globset::Error),
 }
 
 pub fn parse_sources(task: &mut Task, sources: &Yaml) -> Result<(), InvalidSources> {
@@ -48,9 +50,10 @@ pub fn parse_sources(task: &mut Task, sources: &Yaml) -> Result<(), InvalidSourc
         .iter()
         .enumerate()
         .map(|(i, s)| {
-            s.as_str()
-                .ok_or_else(|| InvalidSources::NotAString(i, s.clone()))
-                .map(|s| s.to_string())
+            let s = s.as_str().ok_or_else(|| InvalidSources::NotAString(i, s.clone()))?;
+            // only the syntax is checked here, templates are rendered at instantiation
+            PathPatterns::new("", [s]).map_err(|e| InvalidSources::InvalidPattern(i, e))?;
+            Ok(s.to_string())
         })
         .collect::<Result<_, _>>()?;
     Ok(())
@@ -63,25 +66,21 @@ pub enum InvalidOutputs {
     NotAnArray,
     #[error("Invalid output at index {0}, expected a string but got: {1:?}")]
     NotAString(usize, Yaml),
+    #[error("Invalid output pattern at index {0}: {1}")]
+    InvalidPattern(usize, globset::Error),
 }
 
 
 pub fn parse_outputs(task: &mut Task, outputs: &Yaml) -> Result<(), InvalidOutputs> {
-    task.body.outputs.paths = outputs
-        .as_vec()
-        .ok_or(InvalidOutputs::NotAnArray)?
-        .iter()
-        .enumerate()
-        .map(|(i, s)| {
-            s.as_str()
-                .ok_or_else(|| InvalidOutputs::NotAString(i, s.clone()))
-                .map(|s| if s.ends_with("/") {
-                    OutputPath::Directory(s.to_string())
-                } else {
-                    OutputPath::File(s.to_string())
-                })
-        })
-        .collect::<Result<_, _>>()?;
+    for (i, s) in outputs.as_vec().ok_or(InvalidOutputs::NotAnArray)?.iter().enumerate() {
+        let s = s.as_str().ok_or_else(|| InvalidOutputs::NotAString(i, s.clone()))?;
+        PathPatterns::new("", [s]).map_err(|e| InvalidOutputs::InvalidPattern(i, e))?;
+        if let Some(exclude) = s.strip_prefix('!') {
+            task.body.outputs.exclude.push(exclude.to_string());
+        } else {
+            task.body.outputs.paths.push(OutputPath::new(s));
+        }
+    }
     Ok(())
 }
 
@@ -117,10 +116,14 @@ pub fn parse_params(task: &mut Task, args: &Yaml) -> Result<(), ParamParsingErro
 pub enum InvalidParam {
     #[error("missing parameter type")]
     MissingType,
+    #[error("expected a type or an object, got: {0:?}")]
+    NotATypeOrObject(Yaml),
     #[error("invalid parameter type: {0}")]
     InvalidType(#[from] ParamTypeError),
     #[error("error converting default value for parameter `{0}`: {1}")]
     DefaultValueConversion(String, YamlToJsonError),
+    #[error("default value {1} is not a valid {0}")]
+    InvalidDefault(ArgType, Json),
 }
 
 fn parse_param(value: &Yaml) -> Result<Param, InvalidParam> {
@@ -129,6 +132,11 @@ fn parse_param(value: &Yaml) -> Result<Param, InvalidParam> {
             ty: parse_param_type_str(t)?,
             default: None,
         },
+        // the short form of an array type, e.g. `{array: number}`
+        Yaml::Hash(hash) if !hash.contains_key(&Yaml::String("type".into())) && hash.contains_key(&Yaml::String("array".into())) => Param {
+            ty: parse_param_type(value)?,
+            default: None,
+        },
         Yaml::Hash(hash) => {
             let ty = hash
                 .get(&Yaml::String("type".into()))
@@ -141,9 +149,14 @@ fn parse_param(value: &Yaml) -> Result<Param, InvalidParam> {
                 .transpose()?;
             Param { ty, default }
         }
-        _ => panic!("Unsupported argument type"),
+        _ => return Err(InvalidParam::NotATypeOrObject(value.clone())),
     };
 
+    if !value.validate_default() {
+        let default = value.default.clone().expect("an invalid default must be present");
+        return Err(InvalidParam::InvalidDefault(value.ty, default));
+    }
+
     Ok(value)
 }
 
@@ -154,13 +167,19 @@ pub enum ParamTypeError {
     UnknownType(String),
     #[error("Expected array options to be strings")]
     OptionsNotStrings,
+    #[error("Expected a type name, a list of options or `{{array: <type>}}`, got: {0:?}")]
+    InvalidType(Yaml),
 }
 
 fn parse_param_type(t: &Yaml) -> Result<ArgType, ParamTypeError> {
     match t {
         Yaml::String(t) => parse_param_type_str(t),
         Yaml::Array(options) => parse_param_type_select(options),
-        _ => todo!(),
+        Yaml::Hash(hash) if hash.len() == 1 => match hash.get(&Yaml::String("array".into())) {
+            Some(inner) => Ok(ArgType::Array(Box::new(parse_param_type(inner)?))),
+            None => Err(ParamTypeError::InvalidType(t.clone())),
+        },
+        _ => Err(ParamTypeError::InvalidType(t.clone())),
     }
 }
 
@@ -170,7 +189,8 @@ fn parse_param_type_str(t: &str) -> Result<ArgType, ParamTypeError> {
         "number" => Ok(ArgType::Number),
         "bool" | "boolean" => Ok(ArgType::Boolean),
         "path" => Ok(ArgType::Path),
-        "array" => todo!(),
+        // elements are taken as strings when their type is not given
+        "array" => Ok(ArgType::Array(Box::new(ArgType::String))),
         _ => Err(ParamTypeError::UnknownType(t.to_string())),
     }
 }
@@ -185,3 +205,50 @@ fn parse_param_type_select(options: &[Yaml]) -> Result<ArgType, ParamTypeError>
         .collect::<Result<_, _>>()?;
     Ok(ArgType::Select(options))
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use yaml_rust::YamlLoader;
+
+    use super::*;
+
+    fn param(source: &str) -> Result<Param, InvalidParam> {
+        parse_param(&YamlLoader::load_from_str(source).unwrap()[0])
+    }
+
+    #[test]
+    fn array_params() {
+        let types = [
+            ("array", "array<string>"),
+            ("{array: number}", "array<number>"),
+            ("{array: {array: bool}}", "array<array<boolean>>"),
+            ("{array: [debug, release]}", "array<opt(debug, release)>"),
+            ("{type: array}", "array<string>"),
+            ("{type: {array: path}}", "array<path>"),
+        ];
+        for (source, expected) in types {
+            let parsed = param(source).unwrap();
+            assert_eq!(parsed.ty.to_string(), expected, "{source}");
+            assert_eq!(parsed.default, None, "{source}");
+        }
+
+        assert!(matches!(param("{array: int}"), Err(InvalidParam::InvalidType(ParamTypeError::UnknownType(t))) if t == "int"));
+        assert!(matches!(param("{type: {array: number, of: 2}}"), Err(InvalidParam::InvalidType(ParamTypeError::InvalidType(_)))));
+    }
+
+    #[test]
+    fn array_defaults() {
+        let parsed = param("{type: {array: number}, default: [1, 2.5]}").unwrap();
+        assert_eq!(parsed.default, Some(json!([1, 2.5])));
+
+        let parsed = param("{type: array, default: []}").unwrap();
+        assert_eq!(parsed.default, Some(json!([])));
+
+        let err = param("{type: {array: number}, default: [1, two]}").unwrap_err();
+        assert!(matches!(err, InvalidParam::InvalidDefault(ty, value) if ty.to_string() == "array<number>" && value == json!([1, "two"])));
+
+        let err = param("{type: array, default: a}").unwrap_err();
+        assert!(matches!(err, InvalidParam::InvalidDefault(_, value) if value == json!("a")));
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+
+use yaml_rust::Yaml;
+
+use crate::task::{resources::parse_amount, Task};
+
+#[derive(Debug)]
+#[derive(thiserror::Error)]
+pub enum InvalidResources {
+    #[error("Invalid resources, expected a map")]
+    NotAHash,
+    #[error("Invalid resource name, expected a string but got: {0:?}")]
+    InvalidKey(Yaml),
+    #[error("Invalid amount of `{0}`, expected a non-negative integer with an optional K, M, G or T suffix but got: {1:?}")]
+    InvalidAmount(String, Yaml),
+}
+
+#[derive(Debug)]
+#[derive(thiserror::Error)]
+pub enum InvalidLocks {
+    #[error("Invalid locks, expected an array")]
+    NotAnArray,
+    #[error("Invalid lock at index {0}, expected a string but got: {1:?}")]
+    NotAString(usize, Yaml),
+}
+
+/// Parses a map of resource names to amounts, as used by task `resources` and taskfile `limits`
+pub fn parse_amounts(value: &Yaml) -> Result<BTreeMap<String, u64>, InvalidResources> {
+    value
+        .as_hash()
+        .ok_or(InvalidResources::NotAHash)?
+        .iter()
+        .map(|(key, amount)| {
+            let key = key
+                .as_str()
+                .ok_or_else(|| InvalidResources::InvalidKey(key.clone()))?;
+            let parsed = match amount {
+                Yaml::Integer(i) => u64::try_from(*i).ok(),
+                Yaml::String(s) => parse_amount(s),
+                _ => None,
+            };
+            let parsed = parsed.ok_or_else(|| InvalidResources::InvalidAmount(key.to_string(), amount.clone()))?;
+            Ok((key.to_string(), parsed))
+        })
+        .collect()
+}
+
+pub fn parse_resources(task: &mut Task, resources: &Yaml) -> Result<(), InvalidResources> {
+    task.body.resources = parse_amounts(resources)?;
+    Ok(())
+}
+
+pub fn parse_locks(task: &mut Task, locks: &Yaml) -> Result<(), InvalidLocks> {
+    task.body.locks = locks
+        .as_vec()
+        .ok_or(InvalidLocks::NotAnArray)?
+        .iter()
+        .enumerate()
+        .map(|(i, lock)| {
+            lock.as_str()
+                .map(str::to_string)
+                .ok_or_else(|| InvalidLocks::NotAString(i, lock.clone()))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(())
+}
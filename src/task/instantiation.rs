@@ -4,7 +4,7 @@ use handlebars::{Handlebars, HelperDef, RenderErrorReason};
 use serde_json::Value as Json;
 
 use crate::{
-    command::CommandInstantiationError, task::{instantiate_json_value, BirbRenderContext, Deps, InstantiatedTask, OutputPathInstantiationError, Outputs, Task, TaskBody}, utils::type_checking::{check_type, TypeCheckError}
+    command::CommandInstantiationError, task::{instantiate_json_value, ArgType, BirbRenderContext, Deps, InstantiatedTask, OutputPath, OutputPathInstantiationError, Outputs, PathPatterns, Task, TaskBody}, utils::type_checking::{check_type, parse_typed_value, TypeCheckError}
 };
 
 impl Task {
@@ -13,7 +13,8 @@ impl Task {
         args: &BTreeMap<String, Json>,
         env: &BTreeMap<String, Json>,
     ) -> Result<InstantiatedTask, InstantiationError> {
-        self.check_args(&args)?;
+        let args = &self.with_defaults(args);
+        self.check_args(args)?;
 
         let mut handlebars = init_handlebars();
 
@@ -26,8 +27,9 @@ impl Task {
         }
         let env = &env;
 
-        Ok(InstantiatedTask {
+        let task = InstantiatedTask {
             name: self.name.clone(),
+            args: args.clone(),
             body: TaskBody {
                 workdir: handlebars
                     .render_template(&self.body.workdir.to_string_lossy(), &BirbRenderContext { args, env: &env })?
@@ -37,6 +39,12 @@ impl Task {
                     .map(|(k, v)| (k.clone(), v.clone()))
                     .collect(), // TODO avoid clone
                 phony: self.body.phony,
+                interactive: self.body.interactive,
+                tty: self.body.tty,
+                weight: self.body.weight,
+                resources: self.body.resources.clone(),
+                locks: self.body.locks.clone(),
+                output: self.body.output,
                 outputs: Outputs {
                     paths: self
                         .body
@@ -44,14 +52,19 @@ impl Task {
                         .paths
                         .iter()
                         .map(|file| file.instantiate(&mut handlebars, args, env))
+                        .collect::<Result<Vec<_>, _>>()?
+                        .into_iter()
+                        .flatten()
+                        .collect(),
+                    exclude: self
+                        .body
+                        .outputs
+                        .exclude
+                        .iter()
+                        .map(|exclude| handlebars.render_template(exclude, &BirbRenderContext { args, env }))
                         .collect::<Result<_, _>>()?,
                 },
-                sources: self
-                    .body
-                    .sources
-                    .iter()
-                    .map(|source| handlebars.render_template(source, &BirbRenderContext { args, env }))
-                    .collect::<Result<_, _>>()?,
+                sources: render_lines(&mut handlebars, &self.body.sources, args, env)?,
                 deps: Deps(
                     self.body
                         .deps
@@ -79,7 +92,37 @@ impl Task {
                             .map_err(InstantiationError::CleanStepsInstantiationError)
                     }).transpose()?,
             },
-        })
+        };
+
+        // templates could have produced invalid patterns
+        let output_patterns = task.body.outputs.paths.iter().filter_map(|output| match output {
+            OutputPath::Glob(pattern) => Some(pattern.clone()),
+            _ => None,
+        });
+        let patterns = task
+            .body
+            .sources
+            .iter()
+            .cloned()
+            .chain(output_patterns)
+            .chain(task.body.outputs.exclude.iter().map(|exclude| format!("!{exclude}")));
+        for pattern in patterns {
+            PathPatterns::new(&task.body.workdir, [pattern.as_str()])
+                .map_err(|e| InstantiationError::InvalidPattern(pattern.clone(), e))?;
+        }
+
+        Ok(task)
+    }
+
+    /// Returns the given arguments with the missing ones filled from the parameter defaults
+    pub fn with_defaults(&self, args: &BTreeMap<String, Json>) -> BTreeMap<String, Json> {
+        let mut args = args.clone();
+        for (key, param) in &self.params {
+            if let Some(default) = &param.default {
+                args.entry(key.clone()).or_insert_with(|| default.clone());
+            }
+        }
+        args
     }
 
     pub fn check_args(&self, args: &BTreeMap<String, Json>) -> Result<(), ArgumentsCheckError> {
@@ -101,6 +144,41 @@ impl Task {
         }
         Ok(())
     }
+
+    /// Parses raw `key=value` arguments (e.g. from the command line) against the task parameters
+    ///
+    /// Values are converted according to the parameter type. Array parameters can
+    /// also be given multiple times, in which case the values are concatenated.
+    pub fn parse_args<S: AsRef<str>>(&self, raw: impl IntoIterator<Item = S>) -> Result<BTreeMap<String, Json>, ArgumentsCheckError> {
+        let mut args = BTreeMap::new();
+
+        for arg in raw {
+            let arg = arg.as_ref();
+            let Some((key, value)) = arg.split_once('=') else {
+                return Err(ArgumentsCheckError::Malformed { arg: arg.to_string() });
+            };
+            let key = key.trim();
+
+            let param = self
+                .params
+                .get(key)
+                .ok_or_else(|| ArgumentsCheckError::Unknown { key: key.to_string() })?;
+            let value = parse_typed_value(&param.ty, value).map_err(|err| ArgumentsCheckError::TypeError {
+                key: key.to_string(),
+                err,
+            })?;
+
+            match (args.get_mut(key), &param.ty, value) {
+                (None, _, value) => {
+                    args.insert(key.to_string(), value);
+                }
+                (Some(Json::Array(existing)), ArgType::Array(_), Json::Array(more)) => existing.extend(more),
+                (Some(_), _, _) => return Err(ArgumentsCheckError::Duplicate { key: key.to_string() }),
+            }
+        }
+
+        Ok(args)
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -115,6 +193,8 @@ pub enum InstantiationError {
     StepsInstantiationError(CommandInstantiationError),
     #[error("Failed to instantiate clean steps: {0}")]
     CleanStepsInstantiationError(CommandInstantiationError),
+    #[error("Invalid pattern '{0}': {1}")]
+    InvalidPattern(String, globset::Error),
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -123,9 +203,31 @@ pub enum ArgumentsCheckError {
     NotFound { key: String },
     #[error("Argument '{key}' has invalid type: {err}")]
     TypeError { key: String, err: TypeCheckError },
+    #[error("Unknown argument '{key}'")]
+    Unknown { key: String },
+    #[error("Argument '{key}' given more than once")]
+    Duplicate { key: String },
+    #[error("Invalid argument '{arg}', expected `key=value`")]
+    Malformed { arg: String },
 }
 
 
+/// Renders each template, a template rendering to several lines (e.g. with
+/// `{{#each}}`) gives one entry per non-empty line
+fn render_lines(
+    handlebars: &mut Handlebars,
+    templates: &[String],
+    args: &BTreeMap<String, Json>,
+    env: &BTreeMap<String, Json>,
+) -> Result<Vec<String>, handlebars::RenderError> {
+    let mut lines = Vec::new();
+    for template in templates {
+        let rendered = handlebars.render_template(template, &BirbRenderContext { args, env })?;
+        lines.extend(rendered.lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_string));
+    }
+    Ok(lines)
+}
+
 fn init_handlebars() -> Handlebars<'static> {
     let mut handlebars = Handlebars::new();
     //handlebars.register_escape_fn(handlebars::no_escape);
@@ -165,4 +267,59 @@ impl HelperDef for FmtPrecision {
         out.write(&formatted)?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::task::Param;
+
+    use super::*;
+
+    fn task() -> Task {
+        let mut task = Task::new("test");
+        for (name, ty) in [
+            ("count", ArgType::Number),
+            ("verbose", ArgType::Boolean),
+            ("files", ArgType::Array(Box::new(ArgType::String))),
+        ] {
+            task.params.insert(name.to_string(), Param { ty, default: None });
+        }
+        task
+    }
+
+    #[test]
+    fn parse_args() {
+        let args = task().parse_args(["count= 3", " verbose =yes", "files=a=b"]).unwrap();
+        assert_eq!(args, BTreeMap::from([
+            ("count".to_string(), json!(3)),
+            ("verbose".to_string(), json!(true)),
+            ("files".to_string(), json!(["a=b"])),
+        ]));
+    }
+
+    #[test]
+    fn repeated_arrays_are_concatenated() {
+        let args = task().parse_args(["files=a,b", "files=[\"c\"]", "files="]).unwrap();
+        assert_eq!(args["files"], json!(["a", "b", "c"]));
+    }
+
+    #[test]
+    fn repeated_args_are_rejected() {
+        let err = task().parse_args(["count=1", "count=1"]).unwrap_err();
+        assert!(matches!(err, ArgumentsCheckError::Duplicate { key } if key == "count"));
+    }
+
+    #[test]
+    fn invalid_args() {
+        let err = task().parse_args(["count"]).unwrap_err();
+        assert!(matches!(err, ArgumentsCheckError::Malformed { arg } if arg == "count"));
+
+        let err = task().parse_args(["jobs=2"]).unwrap_err();
+        assert!(matches!(err, ArgumentsCheckError::Unknown { key } if key == "jobs"));
+
+        let err = task().parse_args(["count=many"]).unwrap_err();
+        assert!(matches!(err, ArgumentsCheckError::TypeError { key, err: TypeCheckError::InvalidLiteral { .. } } if key == "count"));
+    }
+}
@@ -59,6 +59,10 @@ pub fn instantiate_json_value(
 ) -> Json {
     match value {
         Json::String(s) => {
+            // a lone expression keeps the type of the value it refers to, e.g. an array
+            if let Some(value) = lookup_expression(s, args, env) {
+                return value;
+            }
             let rendered = handlebars
                 .render_template(s, &BirbRenderContext { args, env })
                 .expect("Failed to render string template");
@@ -80,4 +84,55 @@ pub fn instantiate_json_value(
         }
         _ => value.clone(),
     }
-}
\ No newline at end of file
+}
+
+/// If the template is a single `{{path.to.value}}` expression, returns the value it refers to
+fn lookup_expression(template: &str, args: &impl Serialize, env: &impl Serialize) -> Option<Json> {
+    let path = template.trim().strip_prefix("{{")?.strip_suffix("}}")?.trim();
+    if path.is_empty() || !path.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.') {
+        return None;
+    }
+    let context = serde_json::to_value(BirbRenderContext { args, env }).ok()?;
+    path.split('.').try_fold(&context, |value, key| value.get(key)).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn lone_expressions_keep_their_type() {
+        let args = json!({ "files": ["a", "b c"], "opts": { "level": 3 } });
+        let env = json!({ "CC": "gcc" });
+        let lookup = |template: &str| lookup_expression(template, &args, &env);
+
+        assert_eq!(lookup("{{args.files}}"), Some(json!(["a", "b c"])));
+        assert_eq!(lookup(" {{ args.opts.level }} "), Some(json!(3)));
+        assert_eq!(lookup("{{env.CC}}"), Some(json!("gcc")));
+        assert_eq!(lookup("{{args.missing}}"), None);
+        assert_eq!(lookup("{{args.files}}/x"), None);
+        assert_eq!(lookup("{{#each args.files}}{{this}}{{/each}}"), None);
+    }
+
+    /// An array argument is passed to a dependency as an array, not as its rendering
+    #[test]
+    fn arrays_are_passed_to_dependencies() {
+        let dep = TaskInvocation {
+            r#ref: TaskRef::Name("compile".into()),
+            args: BTreeMap::from([
+                ("files".to_string(), json!("{{args.files}}")),
+                ("more".to_string(), json!(["{{args.files}}", "{{args.count}}"])),
+                ("label".to_string(), json!("{{args.count}} files")),
+            ]),
+        };
+        let args = json!({ "files": ["a.c", "b.c"], "count": 2 });
+        let env = json!({});
+
+        let dep = dep.instantiate(&mut Handlebars::new(), &args, &env);
+        assert_eq!(dep.args["files"], json!(["a.c", "b.c"]));
+        assert_eq!(dep.args["more"], json!([["a.c", "b.c"], 2]));
+        assert_eq!(dep.args["label"], json!("2 files"));
+    }
+}
@@ -0,0 +1,33 @@
+/// How the output of the steps is shown while tasks run in parallel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum OutputMode {
+    /// Lines are printed as they are written
+    Interleaved,
+    /// Lines are printed as they are written, after the number of the task
+    #[default]
+    Prefixed,
+    /// The output of a task is printed in one block once it finishes
+    Grouped,
+    /// Like `grouped`, but only the output of unsuccessful tasks is printed
+    QuietOnSuccess,
+}
+
+impl OutputMode {
+    /// Names of the modes, as written in taskfiles and on the command line
+    pub const NAMES: [&str; 4] = ["interleaved", "prefixed", "grouped", "quiet-on-success"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "interleaved" => Some(Self::Interleaved),
+            "prefixed" => Some(Self::Prefixed),
+            "grouped" => Some(Self::Grouped),
+            "quiet-on-success" => Some(Self::QuietOnSuccess),
+            _ => None,
+        }
+    }
+
+    /// The output is kept until the task finishes
+    pub fn is_buffered(self) -> bool {
+        matches!(self, Self::Grouped | Self::QuietOnSuccess)
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    path::{Component, Path, PathBuf},
+};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use walkdir::WalkDir;
+
+/// A set of paths described by literal paths, glob patterns and `!` exclusions,
+/// relative to a base directory
+///
+/// Literal files are kept as they are, even if they do not exist, while literal
+/// directories and glob patterns are expanded to the files they contain or match.
+/// Exclusions only apply to the expanded files.
+#[derive(Debug, Clone)]
+pub struct PathPatterns {
+    base: PathBuf,
+    literals: Vec<PathBuf>,
+    /// Directories to walk to find the files matching `include`
+    glob_roots: Vec<PathBuf>,
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+/// Returns `true` if the pattern has to be matched instead of being used as a path
+///
+/// Handlebars expressions (`{{...}}`) are not wildcards, only what they render to is.
+pub fn is_glob(pattern: &str) -> bool {
+    let pattern = without_templates(pattern);
+    pattern.contains(['*', '?', '[', '{']) || pattern.starts_with('!')
+}
+
+/// The pattern without its handlebars expressions, an unclosed `{{` is kept
+fn without_templates(pattern: &str) -> Cow<'_, str> {
+    if !pattern.contains("{{") {
+        return Cow::Borrowed(pattern);
+    }
+    let mut result = String::new();
+    let mut rest = pattern;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        rest = &rest[start + end + 2..];
+    }
+    result.push_str(rest);
+    Cow::Owned(result)
+}
+
+impl PathPatterns {
+    pub fn new<'a>(base: impl Into<PathBuf>, patterns: impl IntoIterator<Item = &'a str>) -> Result<Self, globset::Error> {
+        let base = base.into();
+        let mut literals = Vec::new();
+        let mut glob_roots = Vec::new();
+        let mut include = GlobSetBuilder::new();
+        let mut exclude = GlobSetBuilder::new();
+
+        for pattern in patterns {
+            if let Some(pattern) = pattern.strip_prefix('!') {
+                exclude.add(glob(pattern)?);
+            } else if is_glob(pattern) {
+                include.add(glob(pattern)?);
+                glob_roots.push(base.join(glob_root(pattern)));
+            } else {
+                literals.push(base.join(pattern));
+            }
+        }
+
+        Ok(Self {
+            base,
+            literals,
+            glob_roots,
+            include: include.build()?,
+            exclude: exclude.build()?,
+        })
+    }
+
+    /// Expands the patterns, the result is sorted and has no duplicates
+    pub fn expand(&self) -> Vec<PathBuf> {
+        let mut paths = BTreeSet::new();
+
+        for literal in &self.literals {
+            if literal.is_dir() {
+                paths.extend(walk(literal).filter(|path| !self.is_excluded(path)));
+            } else {
+                paths.insert(literal.clone());
+            }
+        }
+
+        for root in self.glob_roots.iter().filter(|root| root.is_dir()) {
+            paths.extend(walk(root).filter(|path| self.is_included(path) && !self.is_excluded(path)));
+        }
+
+        paths.into_iter().collect()
+    }
+
+    /// Directories whose content may match the patterns
+    pub fn roots(&self) -> impl Iterator<Item = &Path> {
+        self.literals
+            .iter()
+            .filter(|literal| literal.is_dir())
+            .chain(&self.glob_roots)
+            .map(PathBuf::as_path)
+    }
+
+    /// Returns `true` if the path would be part of the expansion
+    pub fn matches(&self, path: &Path) -> bool {
+        if self.literals.iter().any(|literal| literal == path) {
+            return true;
+        }
+        let in_literal_dir = self.literals.iter().any(|literal| path.starts_with(literal));
+        (in_literal_dir || self.is_included(path)) && !self.is_excluded(path)
+    }
+
+    fn is_included(&self, path: &Path) -> bool {
+        path.strip_prefix(&self.base).is_ok_and(|rel| self.include.is_match(rel))
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        path.strip_prefix(&self.base).is_ok_and(|rel| self.exclude.is_match(rel))
+    }
+}
+
+fn glob(pattern: &str) -> Result<globset::Glob, globset::Error> {
+    // `*` must not cross directories, only `**` does
+    GlobBuilder::new(pattern).literal_separator(true).build()
+}
+
+/// The leading components of the pattern that contain no wildcards
+fn glob_root(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| match component {
+            Component::Normal(part) => !is_glob(&part.to_string_lossy()),
+            _ => true,
+        })
+        .collect()
+}
+
+/// All the files under a directory, in a deterministic order
+fn walk(dir: &Path) -> impl Iterator<Item = PathBuf> + use<> {
+    WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry.into_path()).filter(|path| path.is_file()),
+            Err(e) => {
+                log::warn!("Failed to walk sources: {e}");
+                None
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn globs() {
+        assert!(is_glob("src/*.c"));
+        assert!(is_glob("src/**/main.c"));
+        assert!(is_glob("file?.txt"));
+        assert!(is_glob("file[0-9].txt"));
+        assert!(is_glob("*.{c,h}"));
+        assert!(is_glob("!target"));
+        assert!(!is_glob("src/main.c"));
+        assert!(!is_glob("build/"));
+    }
+
+    /// Templates are rendered before the pattern is used, only what is around them counts
+    #[test]
+    fn templates_are_not_globs() {
+        assert!(!is_glob("out/{{args.x}}.o"));
+        assert!(!is_glob("build-{{args.cfg}}/"));
+        assert!(!is_glob("{{args.a}}-{{args.b}}"));
+        assert!(!is_glob("{{{args.raw}}}"));
+        assert!(is_glob("{{args.dir}}/*.o"));
+        assert!(is_glob("out/{{args.x}}.{o,d}"));
+        // not a template
+        assert!(is_glob("out/{{args.x"));
+    }
+
+    #[test]
+    fn glob_roots() {
+        assert_eq!(glob_root("src/**/*.c"), Path::new("src"));
+        assert_eq!(glob_root("a/b/c?.txt"), Path::new("a/b"));
+        assert_eq!(glob_root("*.c"), Path::new(""));
+        assert_eq!(glob_root("src/{a,b}/main.c"), Path::new("src"));
+        assert_eq!(glob_root("{{args.dir}}/x/*.o"), Path::new("{{args.dir}}/x"));
+    }
+
+    #[test]
+    fn matches() {
+        let patterns = PathPatterns::new("/base", [
+            "src/**/*.rs",
+            "!src/gen/**",
+            "Cargo.toml",
+            "assets",
+            "!assets/*.tmp",
+            "*.md",
+        ]).unwrap();
+
+        assert!(patterns.matches(Path::new("/base/src/main.rs")));
+        assert!(patterns.matches(Path::new("/base/src/a/b.rs")));
+        assert!(patterns.matches(Path::new("/base/Cargo.toml")));
+        assert!(patterns.matches(Path::new("/base/assets/img.png")));
+        assert!(patterns.matches(Path::new("/base/assets/deep/img.png")));
+        assert!(patterns.matches(Path::new("/base/README.md")));
+
+        // excluded
+        assert!(!patterns.matches(Path::new("/base/src/gen/out.rs")));
+        assert!(!patterns.matches(Path::new("/base/assets/cache.tmp")));
+        // not matched
+        assert!(!patterns.matches(Path::new("/base/src/main.c")));
+        assert!(!patterns.matches(Path::new("/base/docs/README.md")));
+        assert!(!patterns.matches(Path::new("/other/src/main.rs")));
+    }
+
+    #[test]
+    fn expand() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["src/main.rs", "src/lib.rs", "src/gen/out.rs", "src/notes.txt", "assets/a.png", "assets/b.tmp"] {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        let patterns = PathPatterns::new(dir.path(), [
+            "src/**/*.rs",
+            "!src/gen/**",
+            "assets",
+            "!**/*.tmp",
+            "missing.txt",
+        ]).unwrap();
+
+        let expected = ["assets/a.png", "missing.txt", "src/lib.rs", "src/main.rs"]
+            .map(|file| dir.path().join(file));
+        assert_eq!(patterns.expand(), expected);
+    }
+}
@@ -0,0 +1,20 @@
+/// Resource every task needs one of unless it declares otherwise, limited by `-j` by default
+pub const CPU: &str = "cpu";
+
+/// Parses an amount of a resource, an integer with an optional `K`, `M`, `G` or `T` suffix
+///
+/// Suffixes are powers of 1024, e.g. `8G` is 8 GiB when used for memory.
+pub fn parse_amount(amount: &str) -> Option<u64> {
+    let amount = amount.trim();
+    let (digits, multiplier) = match amount.char_indices().last()? {
+        (i, 'K' | 'k') => (&amount[..i], 1 << 10),
+        (i, 'M' | 'm') => (&amount[..i], 1 << 20),
+        (i, 'G' | 'g') => (&amount[..i], 1 << 30),
+        (i, 'T' | 't') => (&amount[..i], 1 << 40),
+        _ => (amount, 1),
+    };
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
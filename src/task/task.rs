@@ -1,4 +1,4 @@
-use std::{collections::HashMap, path::{Path, PathBuf}};
+use std::{collections::{BTreeMap, HashMap}, path::{Path, PathBuf}};
 
 use handlebars::Handlebars;
 use linked_hash_map::LinkedHashMap;
@@ -6,7 +6,7 @@ use serde::Serialize;
 use yaml_rust::Yaml;
 use serde_json::Value as Json;
 
-use crate::{command::Command, task::{from_yaml::{self, InvalidTaskObject}, params::Param, BirbRenderContext, TaskInvocation, TaskRef}};
+use crate::{command::Command, task::{from_yaml::{self, InvalidTaskObject}, params::Param, patterns::is_glob, OutputMode, PathPatterns, BirbRenderContext, TaskInvocation, TaskRef}};
 
 
 #[derive(Debug, Clone)]
@@ -20,20 +20,49 @@ pub struct Task {
 #[derive(Debug, Clone)]
 pub struct InstantiatedTask {
     pub name: String,
+    /// The arguments the task was instantiated with, including defaults
+    pub args: BTreeMap<String, Json>,
     pub body: TaskBody,
 }
 
 impl InstantiatedTask {
-    pub fn resolve_sources(&self) -> impl Iterator<Item = PathBuf> {
-        self.body.sources.iter().map(|source| {
-            let mut path = self.body.workdir.clone();
-            path.push(source);
-            path
-        })
+    pub fn source_patterns(&self) -> PathPatterns {
+        // patterns are validated when the task is instantiated
+        PathPatterns::new(&self.body.workdir, self.body.sources.iter().map(String::as_str))
+            .expect("Invalid source patterns in instantiated task")
+    }
+
+    /// The source files, with globs and directories expanded
+    ///
+    /// The file system is walked on every call, so that the sources generated
+    /// by the dependencies are found once they have run.
+    pub fn resolve_sources(&self) -> Vec<PathBuf> {
+        self.source_patterns().expand()
     }
 
+    /// The outputs, with globs expanded to the existing files they match
+    ///
+    /// A glob that matches nothing is returned as is, so that it is reported as missing.
     pub fn resolve_outputs(&self) -> impl Iterator<Item = OutputPath> {
-        self.body.outputs.paths.iter().map(move |file| file.resolve(&self.body.workdir))
+        self.body.outputs.paths.iter().flat_map(move |output| match output {
+            OutputPath::Glob(pattern) => {
+                let patterns = std::iter::once(pattern.clone())
+                    .chain(self.body.outputs.exclude.iter().map(|exclude| format!("!{exclude}")))
+                    .collect::<Vec<_>>();
+                let matches = PathPatterns::new(&self.body.workdir, patterns.iter().map(String::as_str))
+                    .expect("Invalid output patterns in instantiated task")
+                    .expand();
+                if matches.is_empty() {
+                    vec![output.resolve(&self.body.workdir)]
+                } else {
+                    matches
+                        .into_iter()
+                        .map(|path| OutputPath::File(path.to_string_lossy().to_string()))
+                        .collect()
+                }
+            }
+            _ => vec![output.resolve(&self.body.workdir)],
+        })
     }
 }
 
@@ -42,7 +71,23 @@ pub struct TaskBody {
     pub env: LinkedHashMap<String, Json>,
     pub workdir: PathBuf,
     pub phony: bool,
+    /// The steps read from and write to the terminal instead of pipes, e.g. prompts,
+    /// REPLs or editors. The task runs alone, its output is neither captured nor logged.
+    pub interactive: bool,
+    /// The steps write to pseudo-terminals instead of pipes, so that tools keep their
+    /// colours and progress output. Only on Unix, the output is still captured.
+    pub tty: bool,
+    /// Expected duration in seconds, used to start the longest chains of tasks first
+    pub weight: Option<f64>,
+    /// Amounts of resources (`cpu`, `mem`, ...) the task needs while running,
+    /// a parallel run only starts the task when they are available
+    pub resources: BTreeMap<String, u64>,
+    /// Named locks held while running, tasks sharing a lock don't run at the same time
+    pub locks: Vec<String>,
+    /// How the output is shown in parallel runs, defaults to the `output` of the taskfile
+    pub output: Option<OutputMode>,
     pub outputs: Outputs,
+    /// Paths, glob patterns and `!` exclusions, relative to the workdir
     pub sources: Vec<String>,
     pub deps: Deps,
     pub steps: Vec<Command>,
@@ -59,7 +104,13 @@ impl Task {
                 env: LinkedHashMap::new(),
                 workdir: PathBuf::new(),
                 phony: false,
-                outputs: Outputs { paths: Vec::new() },
+                interactive: false,
+                tty: false,
+                weight: None,
+                resources: BTreeMap::new(),
+                locks: Vec::new(),
+                output: None,
+                outputs: Outputs { paths: Vec::new(), exclude: Vec::new() },
                 sources: Default::default(),
                 deps: Deps(Vec::new()),
                 steps: Default::default(),
@@ -97,22 +148,45 @@ impl Dep {
 #[derive(Debug, Clone)]
 pub struct Outputs {
     pub paths: Vec<OutputPath>,
+    /// Patterns of files that are not outputs even if they match a glob
+    pub exclude: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
 pub enum OutputPath {
     File(String),
     Directory(String),
+    Glob(String),
 }
 
 impl OutputPath {
-    pub fn instantiate(&self, handlebars: &mut Handlebars, args: &impl Serialize, env: &impl Serialize) -> Result<Self, OutputPathInstantiationError> {
-        match self {
-            OutputPath::File(path) => Ok(OutputPath::File(handlebars.render_template(path, &BirbRenderContext { args, env })?)),
-            OutputPath::Directory(path) => Ok(OutputPath::Directory(handlebars.render_template(path, &BirbRenderContext { args, env })?)),
+    /// A glob if the path has wildcards, a directory if it ends with `/`, a file otherwise
+    pub fn new(path: impl Into<String>) -> Self {
+        let path = path.into();
+        if is_glob(&path) {
+            OutputPath::Glob(path)
+        } else if path.ends_with('/') {
+            OutputPath::Directory(path)
+        } else {
+            OutputPath::File(path)
         }
     }
 
+    /// Renders the path, a template rendering to several lines (e.g. with
+    /// `{{#each}}`) gives one output per non-empty line
+    ///
+    /// The rendered paths are classified again, the arguments could have added wildcards.
+    pub fn instantiate(&self, handlebars: &mut Handlebars, args: &impl Serialize, env: &impl Serialize) -> Result<Vec<Self>, OutputPathInstantiationError> {
+        let (OutputPath::File(template) | OutputPath::Directory(template) | OutputPath::Glob(template)) = self;
+        let rendered = handlebars.render_template(template, &BirbRenderContext { args, env })?;
+        Ok(rendered
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(OutputPath::new)
+            .collect())
+    }
+
     pub fn resolve(&self, workdir: &PathBuf) -> Self {
         let mut path = workdir.clone();
         match self {
@@ -124,6 +198,10 @@ impl OutputPath {
                 path.push(dir);
                 OutputPath::Directory(path.to_string_lossy().to_string())
             }
+            OutputPath::Glob(pattern) => {
+                path.push(pattern);
+                OutputPath::Glob(path.to_string_lossy().to_string())
+            }
         }
     }
 }
@@ -131,7 +209,7 @@ impl OutputPath {
 impl AsRef<Path> for OutputPath {
     fn as_ref(&self) -> &Path {
         match self {
-            OutputPath::File(path) | OutputPath::Directory(path) => path.as_ref(),
+            OutputPath::File(path) | OutputPath::Directory(path) | OutputPath::Glob(path) => path.as_ref(),
         }
     }
 }
@@ -141,3 +219,52 @@ pub enum OutputPathInstantiationError {
     #[error("Failed to render template: {0}")]
     TemplateRenderError(#[from] handlebars::RenderError),
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Templated outputs keep their meaning, unless the arguments add wildcards
+    #[test]
+    fn templated_outputs() {
+        let args = json!({ "cfg": "v6", "name": "main", "pattern": "*.o" });
+        let env = BTreeMap::<String, Json>::new();
+        let instantiate = |path: &str| {
+            let output = OutputPath::new(path);
+            let mut rendered = output.instantiate(&mut Handlebars::new(), &args, &env).unwrap();
+            assert_eq!(rendered.len(), 1);
+            (output, rendered.remove(0))
+        };
+
+        let (declared, rendered) = instantiate("build-{{args.cfg}}/");
+        assert!(matches!(declared, OutputPath::Directory(_)));
+        assert!(matches!(rendered, OutputPath::Directory(path) if path == "build-v6/"));
+
+        let (declared, rendered) = instantiate("out/{{args.name}}.o");
+        assert!(matches!(declared, OutputPath::File(_)));
+        assert!(matches!(rendered, OutputPath::File(path) if path == "out/main.o"));
+
+        let (declared, rendered) = instantiate("out/{{args.pattern}}");
+        assert!(matches!(declared, OutputPath::File(_)));
+        assert!(matches!(rendered, OutputPath::Glob(path) if path == "out/*.o"));
+    }
+
+    /// Sources generated by a dependency are found even if the sources were resolved before
+    #[test]
+    fn sources_generated_by_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.c"), "").unwrap();
+        let mut task = Task::new("build");
+        task.body.workdir = dir.path().to_path_buf();
+        task.body.sources = vec!["*.c".to_string()];
+        let task = task.instantiate(&BTreeMap::new(), &BTreeMap::new()).unwrap();
+
+        // e.g. by a dry-run before the dependencies run
+        assert_eq!(task.resolve_sources(), [dir.path().join("a.c")]);
+        // written by the dependency
+        std::fs::write(dir.path().join("gen.c"), "").unwrap();
+        assert_eq!(task.resolve_sources(), [dir.path().join("a.c"), dir.path().join("gen.c")]);
+    }
+}
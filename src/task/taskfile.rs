@@ -6,7 +6,7 @@ use pathdiff::diff_paths;
 use yaml_rust::{Yaml, YamlLoader};
 use serde_json::Value as Json;
 
-use crate::{cli::CliRunOptions, run::{run_manager::{default::DefaultRunManager, parallel::ParallelRunManager}, RunError}, task::{from_yaml::{yaml_to_json, InvalidTaskObject, YamlToJsonError}, Task, TaskInvocation, TaskRef, Workspace, WorkspaceLoadError}};
+use crate::{cli::{CliRunOptions, MessageFormat}, run::{interrupt::Interrupt, jobserver::Jobserver, logs::RunLogs, report::RunReport, run_manager::{ci::CiRunManager, default::DefaultRunManager, json::JsonRunManager, parallel::ParallelRunManager, RunManager}, trace::Trace, RunError, RunOptions}, task::{from_yaml::{parse_amounts, parse_output_mode, yaml_to_json, InvalidResources, InvalidTaskObject, YamlToJsonError}, OutputMode, Task, TaskInvocation, TaskRef, Workspace, WorkspaceLoadError}};
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum TaskfileId {
@@ -70,6 +70,12 @@ pub struct Taskfile {
 
     pub env: BTreeMap<String, Json>,
 
+    /// Available amounts of resources and locks in parallel runs, see [`TaskBody::resources`](crate::task::TaskBody::resources)
+    pub limits: BTreeMap<String, u64>,
+
+    /// How the output of the tasks is shown in parallel runs, unless a task sets its own
+    pub output: Option<OutputMode>,
+
     /// The tasks in this collection, keyed by their names
     pub tasks: LinkedHashMap<String, Task>,
 }
@@ -119,10 +125,17 @@ impl Taskfile {
             dir,
             imports: Default::default(),
             env: Default::default(),
+            limits: Default::default(),
+            output: None,
             tasks: Default::default(),
         }
     }
 
+    /// Directory where birb keeps its state (build state, logs, ...) for this taskfile
+    pub fn state_dir(&self) -> PathBuf {
+        self.dir.join(".birb")
+    }
+
     /// Finds the taskfile in the current directory or any parent directory
     pub fn find_taskfile(from: impl AsRef<Path>) -> Option<TaskfileSource> {
         const YAML_DATA_EXTENSIONS: &[&str] = &["yml", "yaml", "json"];
@@ -312,6 +325,14 @@ impl Taskfile {
                 }
             }
 
+            if let Some(limits) = doc.get(&Yaml::String("limits".into())) {
+                this.limits.extend(parse_amounts(limits).map_err(YamlDocumentFormatError::InvalidLimits)?);
+            }
+
+            if let Some(output) = doc.get(&Yaml::String("output".into())) {
+                this.output = Some(parse_output_mode(output).ok_or(YamlDocumentFormatError::InvalidOutput)?);
+            }
+
             let tasks = doc
                 .get(&Yaml::String("tasks".into()))
                 .ok_or(YamlDocumentFormatError::MissingTasksKey)?
@@ -322,8 +343,9 @@ impl Taskfile {
                 let key = key
                     .as_str()
                     .ok_or_else(|| YamlDocumentFormatError::InvalidTaskKey(key.clone()))?;
-                let task = Task::from_yaml(&this.dir, key, value)
+                let mut task = Task::from_yaml(&this.dir, key, value)
                     .map_err(|e| YamlDocumentFormatError::InvalidTaskObject(key.to_string(), e))?;
+                task.body.output = task.body.output.or(this.output);
                 this.tasks.insert(key.to_string(), task.clone());
             }
         }
@@ -331,34 +353,82 @@ impl Taskfile {
         Ok(this)
     }
 
-    pub fn invoke(&self, workspace: &Workspace, req: &TaskInvocation<TaskRef>, options: &CliRunOptions) -> Result<(), RunError> {
-        if let Some(max_concurrency) = options.threads.map(|t| t.get_num_threads()) {
-            // multi-threaded run, even if max_concurrency is 1
-            tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .expect("Failed to build Tokio runtime")
-                .block_on({
+    /// Runs the task and its dependencies
+    ///
+    /// Task failures don't make this fail, they are part of the returned report.
+    pub fn invoke(&self, workspace: &Workspace, req: &TaskInvocation<TaskRef>, options: &CliRunOptions, interrupt: &Interrupt) -> Result<RunReport, RunError> {
+        let parallel = max_concurrency(options).is_some();
+        match (options.message_format.unwrap_or_else(MessageFormat::detect), parallel) {
+            (MessageFormat::Json, _) => self.invoke_with(workspace, req, options, JsonRunManager(options.clone()), interrupt),
+            (MessageFormat::Human, true) => self.invoke_with(workspace, req, options, ParallelRunManager(options.clone()), interrupt),
+            (MessageFormat::Human, false) => self.invoke_with(workspace, req, options, DefaultRunManager(options.clone()), interrupt),
+            (MessageFormat::Ci, _) => self.invoke_with(workspace, req, options, CiRunManager { options: options.clone(), parallel }, interrupt),
+        }
+    }
+
+    fn invoke_with(&self, workspace: &Workspace, req: &TaskInvocation<TaskRef>, options: &CliRunOptions, run_manager: impl RunManager + 'static, interrupt: &Interrupt) -> Result<RunReport, RunError> {
+        let run_options = RunOptions {
+            keep_going: options.keep_going,
+            trace: options.trace.as_ref().map(|_| Trace::new()),
+            limits: options.limits.iter().map(|limit| (limit.name.clone(), limit.amount)).collect(),
+            // under make or another birb, the tokens of the parent are used instead of `-j`
+            jobserver: Jobserver::inherited().or_else(|| {
+                Jobserver::new(options.threads?.get_num_threads())
+                    .inspect_err(|e| log::warn!("Failed to create the jobserver: {e}"))
+                    .ok()
+            }),
+            logs: Some(Arc::new(RunLogs::new(self.state_dir()))),
+        };
+        let report = runtime().block_on(async {
+            match max_concurrency(options) {
+                // the tasks run concurrently, even if max_concurrency is 1
+                Some(max_concurrency) => {
                     assert!(max_concurrency > 0);
-                    let options = options.clone();
-                    let run = crate::run::run_parallel(workspace, self, req, ParallelRunManager(options), max_concurrency);
-                    async move {
-                        let r = run.await;
-                        r
-                    }
-                })
-        } else {
-            // single-threaded run
-            crate::run::run(workspace, &self, req, DefaultRunManager(options))
+                    crate::run::run_parallel(workspace, self, req, run_manager, max_concurrency, &run_options, interrupt).await
+                },
+                // one task at a time
+                None => crate::run::run(workspace, self, req, run_manager, &run_options, interrupt).await,
+            }
+        });
+
+        if let Some((trace, path)) = run_options.trace.zip(options.trace.as_ref()) {
+            trace.write(path).unwrap_or_else(|e| log::error!("Failed to write trace to {}: {e}", path.display()));
         }
+
+        report
     }
 
     pub fn clean(&self, workspace: &Workspace, req: &TaskInvocation<TaskRef>, recursive: bool) -> Result<(), RunError> {
-        if recursive {
-            crate::run::clean(workspace, &self, req)
-        } else {
-            crate::run::clean_only(workspace, self, req)
-        }
+        runtime().block_on(async {
+            if recursive {
+                crate::run::clean(workspace, self, req).await
+            } else {
+                crate::run::clean_only(workspace, self, req).await
+            }
+        })
+    }
+}
+
+/// The runtime driving the tasks and their steps
+///
+/// Multi-threaded, so that the trigger checks of a task can block their
+/// worker while the steps of the other tasks keep running.
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to build Tokio runtime")
+}
+
+/// Number of tasks run at the same time in a parallel run, `None` for a sequential run
+///
+/// Runs under the jobserver of a parent process are parallel, limited by the
+/// tokens of the parent and by `-j`, or the number of CPUs.
+fn max_concurrency(options: &CliRunOptions) -> Option<usize> {
+    match (Jobserver::inherited(), options.threads) {
+        (Some(_), threads) => Some(threads.map_or_else(num_cpus::get, |threads| threads.get_num_threads())),
+        (None, Some(threads)) => Some(threads.get_num_threads()),
+        (None, None) => None,
     }
 }
 
@@ -403,4 +473,8 @@ pub enum YamlDocumentFormatError {
     InvalidEnvKey(Yaml),
     #[error("Invalid environment value for `{0}`: {1}")]
     InvalidEnvValue(String, YamlToJsonError),
+    #[error("Invalid limits: {0}")]
+    InvalidLimits(InvalidResources),
+    #[error("Invalid output, expected one of interleaved, prefixed, grouped or quiet-on-success")]
+    InvalidOutput,
 }
\ No newline at end of file
@@ -18,6 +18,11 @@ impl Workspace {
         self.tasks.get(id)
     }
 
+    /// All the taskfiles loaded in this workspace
+    pub fn taskfiles(&self) -> impl Iterator<Item = &Taskfile> {
+        self.tasks.values()
+    }
+
     pub fn get_id_from_path<'a>(&'a self, path: impl AsRef<Path>) -> Option<TaskfileId> {
         self.tasks.get(&TaskfileId::from_path(path.as_ref())).map(|t| t.id.clone())
     }
@@ -79,10 +84,12 @@ impl Workspace {
         }
     }
 
-    // TODO unused?
     pub fn resolve_invocation<'a>(&'a self, current: &'a Taskfile, invocation: &TaskInvocation<TaskRef>) -> Option<(ResolvedTaskInvocation, &'a Task)> {
         let (tasks, task) = self.resolve_task(current, &invocation.r#ref)?;
-        let resolved = invocation.as_resolved(tasks);
+        let mut resolved = invocation.as_resolved(tasks);
+        // defaults are part of the identity of the invocation, so that `task` and
+        // `task arg=<default>` are the same node in the dependency graph
+        resolved.args = task.with_defaults(&resolved.args);
         Some((resolved, task))
     }
 
@@ -1,4 +1,4 @@
-use serde_json::Value as Json;
+use serde_json::{Number, Value as Json};
 
 use crate::task::ArgType;
 
@@ -57,6 +57,63 @@ pub fn check_type(ty: &ArgType, value: &Json) -> Result<(), TypeCheckError> {
     }
 }
 
+/// Parses a raw string (e.g. from the command line) into a value of the given type
+///
+/// Arrays can be given either as a JSON array (`[1, 2]`) or as a comma-separated
+/// list (`a,b,c`), in which case each element is parsed with the inner type.
+pub fn parse_typed_value(ty: &ArgType, raw: &str) -> Result<Json, TypeCheckError> {
+    let invalid = || TypeCheckError::InvalidLiteral {
+        expected: ty.clone(),
+        value: raw.to_string(),
+    };
+    match ty {
+        ArgType::String | ArgType::Path => Ok(Json::String(raw.to_string())),
+        ArgType::Select(options) => {
+            if options.iter().any(|opt| opt == raw) {
+                Ok(Json::String(raw.to_string()))
+            } else {
+                Err(TypeCheckError::InvalidOption {
+                    expected: options.clone(),
+                    value: raw.to_string(),
+                })
+            }
+        }
+        ArgType::Number => {
+            let raw = raw.trim();
+            if let Ok(n) = raw.parse::<i64>() {
+                Ok(Json::Number(n.into()))
+            } else if let Ok(n) = raw.parse::<u64>() {
+                Ok(Json::Number(n.into()))
+            } else {
+                raw.parse::<f64>()
+                    .ok()
+                    .and_then(Number::from_f64)
+                    .map(Json::Number)
+                    .ok_or_else(invalid)
+            }
+        }
+        ArgType::Boolean => match raw.trim() {
+            "true" | "yes" | "on" | "1" => Ok(Json::Bool(true)),
+            "false" | "no" | "off" | "0" => Ok(Json::Bool(false)),
+            _ => Err(invalid()),
+        },
+        ArgType::Array(inner_type) => {
+            if raw.trim_start().starts_with('[') {
+                let value: Json = serde_json::from_str(raw).map_err(|_| invalid())?;
+                check_type(ty, &value)?;
+                Ok(value)
+            } else if raw.trim().is_empty() {
+                Ok(Json::Array(Vec::new()))
+            } else {
+                raw.split(',')
+                    .map(|item| parse_typed_value(inner_type, item.trim()))
+                    .collect::<Result<_, _>>()
+                    .map(Json::Array)
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, thiserror::Error)]
 pub enum TypeCheckError {
     #[error("Expected {expected}")]
@@ -66,4 +123,71 @@ pub enum TypeCheckError {
         expected: Vec<String>,
         value: String,
     },
+    #[error("Cannot parse `{value}` as {expected}")]
+    InvalidLiteral { expected: ArgType, value: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn array_of(ty: ArgType) -> ArgType {
+        ArgType::Array(Box::new(ty))
+    }
+
+    #[test]
+    fn booleans() {
+        for raw in ["true", "yes", "on", "1", " yes "] {
+            assert_eq!(parse_typed_value(&ArgType::Boolean, raw).unwrap(), json!(true), "{raw}");
+        }
+        for raw in ["false", "no", "off", "0"] {
+            assert_eq!(parse_typed_value(&ArgType::Boolean, raw).unwrap(), json!(false), "{raw}");
+        }
+        assert!(matches!(
+            parse_typed_value(&ArgType::Boolean, "maybe"),
+            Err(TypeCheckError::InvalidLiteral { value, .. }) if value == "maybe"
+        ));
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_typed_value(&ArgType::Number, " 42 ").unwrap(), json!(42));
+        assert_eq!(parse_typed_value(&ArgType::Number, "-3").unwrap(), json!(-3));
+        assert_eq!(parse_typed_value(&ArgType::Number, "18446744073709551615").unwrap(), json!(u64::MAX));
+        assert_eq!(parse_typed_value(&ArgType::Number, "\t1.5\n").unwrap(), json!(1.5));
+        assert!(parse_typed_value(&ArgType::Number, "1.5.0").is_err());
+        assert!(parse_typed_value(&ArgType::Number, "NaN").is_err());
+    }
+
+    #[test]
+    fn strings_are_kept_as_is() {
+        assert_eq!(parse_typed_value(&ArgType::String, " a, b ").unwrap(), json!(" a, b "));
+        assert_eq!(parse_typed_value(&ArgType::Path, "./x y").unwrap(), json!("./x y"));
+
+        let select = ArgType::Select(vec!["debug".into(), "release".into()]);
+        assert_eq!(parse_typed_value(&select, "release").unwrap(), json!("release"));
+        assert!(matches!(parse_typed_value(&select, "fast"), Err(TypeCheckError::InvalidOption { .. })));
+    }
+
+    #[test]
+    fn arrays() {
+        let numbers = array_of(ArgType::Number);
+        assert_eq!(parse_typed_value(&numbers, "[1, 2.5]").unwrap(), json!([1, 2.5]));
+        assert_eq!(parse_typed_value(&numbers, "1, 2 ,3").unwrap(), json!([1, 2, 3]));
+        assert_eq!(parse_typed_value(&numbers, "").unwrap(), json!([]));
+        assert_eq!(parse_typed_value(&numbers, "[]").unwrap(), json!([]));
+        assert!(matches!(parse_typed_value(&numbers, "[1, \"a\"]"), Err(TypeCheckError::MismatchedType { .. })));
+        assert!(matches!(parse_typed_value(&numbers, "[1,"), Err(TypeCheckError::InvalidLiteral { .. })));
+        assert!(matches!(parse_typed_value(&numbers, "1,a"), Err(TypeCheckError::InvalidLiteral { value, .. }) if value == "a"));
+
+        // a JSON literal keeps commas inside the elements
+        let strings = array_of(ArgType::String);
+        assert_eq!(parse_typed_value(&strings, r#"["a,b", "c"]"#).unwrap(), json!(["a,b", "c"]));
+        assert_eq!(parse_typed_value(&strings, "a,b, c").unwrap(), json!(["a", "b", "c"]));
+
+        let nested = array_of(array_of(ArgType::Boolean));
+        assert_eq!(parse_typed_value(&nested, "[[true], []]").unwrap(), json!([[true], []]));
+    }
 }